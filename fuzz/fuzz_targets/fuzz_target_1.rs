@@ -14,6 +14,7 @@ fuzz_target!(|data: &[u8]| {
                 ),
                 ..Default::default()
             }],
+            ..Default::default()
         },
     };
 
@@ -22,9 +23,13 @@ fuzz_target!(|data: &[u8]| {
             pkt,
             serverip: "192.168.0.1".parse().unwrap(),
             ifindex: 1,
+            ifname: Some("eth0".into()),
         };
 
-        if let Ok(reply) = erbium::dhcp::handle_pkt(&mut pools, &request, serverids, &cfg) {
+        if let Ok(erbium::dhcp::Handled {
+            reply: Some(reply), ..
+        }) = erbium::dhcp::handle_pkt(&mut pools, &request, serverids, &cfg)
+        {
             let _ = reply.serialise();
         }
     }
@@ -36,9 +36,10 @@ async fn go() -> Result<(), Box<dyn Error>> {
     };
     let netinfo = erbium::net::netinfo::SharedNetInfo::new().await;
     let conf = erbium::config::load_config_from_path(config_file).await?;
+    let reloads = erbium::config::reload_on_sighup(config_file.to_path_buf(), conf.clone())?;
     let mut services = futures::stream::FuturesUnordered::new();
 
-    services.push(tokio::spawn(dhcp::run(netinfo, conf)));
+    services.push(tokio::spawn(dhcp::run(netinfo, conf, reloads)));
 
     while let Some(x) = services.next().await {
         println!("Service complete: {:?}", x)
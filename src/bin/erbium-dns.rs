@@ -25,7 +25,7 @@ use erbium::dns;
 
 async fn go() -> Result<(), Box<dyn Error>> {
     let args: Vec<_> = std::env::args_os().collect();
-    let _config_file = match args.len() {
+    let config_file = match args.len() {
         1 => std::path::Path::new("erbium.conf"),
         2 => std::path::Path::new(&args[1]),
         _ => {
@@ -33,9 +33,12 @@ async fn go() -> Result<(), Box<dyn Error>> {
             return Ok(());
         }
     };
+    let netinfo = erbium::net::netinfo::SharedNetInfo::new().await;
+    let conf = erbium::config::load_config_from_path(config_file).await?;
+    erbium::config::reload_on_sighup(config_file.to_path_buf(), conf.clone())?;
     let mut services = futures::stream::FuturesUnordered::new();
 
-    services.push(tokio::spawn(dns::run()));
+    services.push(tokio::spawn(dns::run(netinfo, conf)));
 
     while let Some(x) = services.next().await {
         println!("Service complete: {:?}", x)
@@ -0,0 +1,199 @@
+/*   Copyright 2020 Perry Lorier
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *  SPDX-License-Identifier: Apache-2.0
+ *
+ *  Lists the leases in the DHCP lease database.
+ */
+
+extern crate erbium;
+
+use erbium::dhcp::pool;
+
+fn usage(name: &str) -> ! {
+    eprintln!(
+        "Usage: {} [--json] [--all] [--subnet <prefix/len>] [--mac <hardware-address>] [<leasedb>]",
+        name
+    );
+    eprintln!("  --json     Output leases as JSON instead of a table");
+    eprintln!("  --all      Include expired leases");
+    eprintln!("  --subnet   Only show leases inside this subnet");
+    eprintln!("  --mac      Only show leases for this hardware address");
+    std::process::exit(2);
+}
+
+/* Invalid arguments exit non-zero, so scripts can tell them apart from an empty lease list */
+fn bad_argument(msg: &str) -> ! {
+    eprintln!("{}", msg);
+    std::process::exit(2);
+}
+
+fn parse_subnet(s: &str) -> Option<erbium::net::Ipv4Subnet> {
+    let mut it = s.splitn(2, '/');
+    let addr = it.next()?.parse().ok()?;
+    let prefixlen = it.next()?.parse().ok()?;
+    erbium::net::Ipv4Subnet::new(addr, prefixlen).ok()
+}
+
+fn parse_mac(s: &str) -> Option<Vec<u8>> {
+    s.split(':')
+        .map(|x| u8::from_str_radix(x, 16).ok())
+        .collect()
+}
+
+fn format_hex(v: &Option<Vec<u8>>, sep: &str) -> String {
+    v.as_ref()
+        .map(|v| {
+            v.iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<Vec<String>>()
+                .join(sep)
+        })
+        .unwrap_or_else(|| "-".into())
+}
+
+fn json_hex(v: &Option<Vec<u8>>, sep: &str) -> String {
+    match v {
+        Some(_) => format!("\"{}\"", format_hex(v, sep)),
+        None => "null".into(),
+    }
+}
+
+/* Host names etc are supplied by clients, so could contain anything */
+fn json_string(v: &Option<String>) -> String {
+    match v {
+        Some(s) => {
+            let mut out = String::from("\"");
+            for c in s.chars() {
+                match c {
+                    '"' => out += "\\\"",
+                    '\\' => out += "\\\\",
+                    c if (c as u32) < 0x20 => out += &format!("\\u{:04x}", c as u32),
+                    c => out.push(c),
+                }
+            }
+            out.push('"');
+            out
+        }
+        None => "null".into(),
+    }
+}
+
+/* Formats a timestamp relative to now, eg "2h30m ago" or "in 5m" */
+fn format_relative(ts: u64, now: u64) -> String {
+    let mut delta = if ts > now { ts - now } else { now - ts };
+    let mut s = String::new();
+    for (unit, secs) in &[("d", 86400), ("h", 3600), ("m", 60), ("s", 1)] {
+        if delta >= *secs || (s.is_empty() && *secs == 1) {
+            s += &format!("{}{}", delta / secs, unit);
+            delta %= secs;
+        }
+    }
+    if ts > now {
+        format!("in {}", s)
+    } else {
+        format!("{} ago", s)
+    }
+}
+
+fn print_table(leases: &[pool::LeaseInfo], now: u64) {
+    println!(
+        "{:<15} {:<17} {:<20} {:<20} {:<16} {:<16} {}",
+        "Address", "Hardware Address", "Client ID", "Host Name", "Start", "Expiry", "State"
+    );
+    for lease in leases {
+        println!(
+            "{:<15} {:<17} {:<20} {:<20} {:<16} {:<16} {}",
+            lease.address.to_string(),
+            format_hex(&lease.client.chaddr, ":"),
+            format_hex(&lease.clientid, ""),
+            lease
+                .client
+                .hostname
+                .as_ref()
+                .map(|h| h.escape_debug().to_string())
+                .unwrap_or_else(|| "-".into()),
+            format_relative(lease.start, now),
+            format_relative(lease.expiry, now),
+            if lease.is_active(now) {
+                "active"
+            } else {
+                "expired"
+            }
+        );
+    }
+}
+
+fn print_json(leases: &[pool::LeaseInfo], now: u64) {
+    let entries: Vec<String> = leases
+        .iter()
+        .map(|lease| {
+            format!(
+                "{{\"address\": \"{}\", \"chaddr\": {}, \"clientid\": {}, \"hostname\": {}, \"vendor_class\": {}, \"policy\": {}, \"start\": {}, \"expiry\": {}, \"active\": {}}}",
+                lease.address,
+                json_hex(&lease.client.chaddr, ":"),
+                json_hex(&lease.clientid, ""),
+                json_string(&lease.client.hostname),
+                json_string(&lease.client.vendor_class),
+                json_string(&lease.client.policy),
+                lease.start,
+                lease.expiry,
+                lease.is_active(now),
+            )
+        })
+        .collect();
+    println!("[{}]", entries.join(",\n "));
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    let mut filter: pool::LeaseFilter = Default::default();
+    let mut json = false;
+    let mut database = std::path::PathBuf::from(pool::DEFAULT_LEASE_DATABASE);
+    let mut it = args.iter().skip(1);
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--all" => filter.include_expired = true,
+            "--subnet" => match it.next().and_then(|s| parse_subnet(s)) {
+                Some(subnet) => filter.subnet = Some(subnet),
+                None => bad_argument("--subnet expects a prefix, eg 192.0.2.0/24"),
+            },
+            "--mac" => match it.next().and_then(|s| parse_mac(s)) {
+                Some(mac) => filter.chaddr = Some(mac),
+                None => bad_argument("--mac expects a hardware address, eg 00:00:5e:00:53:01"),
+            },
+            x if !x.starts_with('-') => database = x.into(),
+            _ => usage(&args[0]),
+        }
+    }
+
+    /* Opened read only, so this works without write access to the database, and doesn't create
+     * it if it doesn't exist.
+     */
+    if !database.exists() {
+        return Err(format!("Lease database {} does not exist", database.display()).into());
+    }
+    let pool = pool::Pool::open_read_only(&database)?;
+    let leases = pool.get_leases(&filter)?;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)?
+        .as_secs();
+    if json {
+        print_json(&leases, now);
+    } else {
+        print_table(&leases, now);
+    }
+    Ok(())
+}
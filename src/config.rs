@@ -18,17 +18,21 @@
  */
 use std::os::unix::fs::PermissionsExt;
 use tokio::io::AsyncReadExt;
-use yaml_rust::yaml::YamlLoader;
+use yaml_rust::yaml::{Yaml, YamlLoader};
 
 #[derive(Debug)]
 pub enum Error {
     IoError(std::io::Error),
     Utf8Error(std::string::FromUtf8Error),
     DhcpError(crate::dhcp::config::Error),
+    DnsError(crate::dns::config::Error),
     YamlError(yaml_rust::scanner::ScanError),
     MissingConfig,
     MultipleConfigs,
     ConfigProcessFailed,
+    ConflictingConfig(String, std::path::PathBuf, std::path::PathBuf),
+    InvalidInclude(String),
+    InFile(std::path::PathBuf, Box<Error>),
 }
 
 impl std::fmt::Display for Error {
@@ -39,12 +43,22 @@ impl std::fmt::Display for Error {
                 write!(f, "UTF8 Decoding error reading configuration file: {}", e)
             }
             Error::DhcpError(e) => write!(f, "DHCP Config loading error: {}", e),
+            Error::DnsError(e) => write!(f, "DNS Config loading error: {}", e),
             Error::YamlError(e) => write!(f, "Yaml parse error while reading configuration: {}", e),
             Error::MissingConfig => write!(f, "Configuration is empty/missing"),
             Error::MultipleConfigs => {
                 write!(f, "Configuration file contains multiple configurations")
             }
             Error::ConfigProcessFailed => write!(f, "Configuration process failed"),
+            Error::ConflictingConfig(key, first, second) => write!(
+                f,
+                "{} is set in both {} and {}",
+                key,
+                first.display(),
+                second.display()
+            ),
+            Error::InvalidInclude(e) => write!(f, "Invalid include: {}", e),
+            Error::InFile(path, e) => write!(f, "{}: {}", path.display(), e),
         }
     }
 }
@@ -54,34 +68,46 @@ impl std::error::Error for Error {}
 #[derive(Debug)]
 pub struct Config {
     pub dhcp: crate::dhcp::config::Config,
+    pub dns: crate::dns::config::Config,
 }
 
 pub type SharedConfig = std::sync::Arc<tokio::sync::Mutex<Config>>;
 
-fn load_config_from_string(cfg: &str) -> Result<SharedConfig, Error> {
+/// Notified each time the configuration has been reloaded.
+pub type ConfigReloads = tokio::sync::watch::Receiver<()>;
+
+fn parse_yaml(cfg: &str) -> Result<Yaml, Error> {
     let mut y = YamlLoader::load_from_str(cfg).map_err(Error::YamlError)?;
     match y.len() {
-        0 => return Err(Error::MissingConfig),
-        1 => (),
-        _ => return Err(Error::MultipleConfigs),
+        0 => Err(Error::MissingConfig),
+        1 => Ok(y.remove(0)),
+        _ => Err(Error::MultipleConfigs),
     }
-    let conf = Config {
-        dhcp: crate::dhcp::config::Config::new(&mut y[0]).map_err(Error::DhcpError)?,
-    };
-    Ok(std::sync::Arc::new(tokio::sync::Mutex::new(conf)))
+}
+
+#[cfg(test)]
+fn parse_config(cfg: &str) -> Result<Config, Error> {
+    build_config(parse_yaml(cfg)?)
+}
+
+fn build_config(mut y: Yaml) -> Result<Config, Error> {
+    Ok(Config {
+        dhcp: crate::dhcp::config::Config::new(&mut y).map_err(Error::DhcpError)?,
+        dns: crate::dns::config::Config::new(&y).map_err(Error::DnsError)?,
+    })
 }
 
 #[cfg(test)]
 pub fn load_config_from_string_for_test(cfg: &str) -> Result<SharedConfig, Error> {
-    load_config_from_string(cfg)
+    Ok(std::sync::Arc::new(tokio::sync::Mutex::new(parse_config(
+        cfg,
+    )?)))
 }
 
 /* We support reading configs from a yaml file, _or_ a program (eg a shell script?) that outputs
  * yaml on stdout.
- *
- * TODO: Implement reading a directory of configs.
  */
-pub async fn load_config_from_path(path: &std::path::Path) -> Result<SharedConfig, Error> {
+async fn read_fragment(path: &std::path::Path) -> Result<Option<Yaml>, Error> {
     let metadata = std::fs::metadata(path).map_err(Error::IoError)?;
     let configdata = if metadata.permissions().mode() & 0o111 != 0 {
         let output = tokio::process::Command::new(path)
@@ -104,12 +130,209 @@ pub async fn load_config_from_path(path: &std::path::Path) -> Result<SharedConfi
         String::from_utf8(contents).map_err(Error::Utf8Error)?
     };
 
-    load_config_from_string(&configdata)
+    match parse_yaml(&configdata) {
+        Ok(y) => Ok(Some(y)),
+        /* Fragments with nothing but comments are fine */
+        Err(Error::MissingConfig) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/* Where each setting in a merged configuration came from, so conflicts can name both files. */
+type Origins = std::collections::HashMap<String, std::path::PathBuf>;
+
+fn origin_of(origins: &Origins, key: &str) -> std::path::PathBuf {
+    let mut key = key;
+    loop {
+        if let Some(origin) = origins.get(key) {
+            return origin.clone();
+        }
+        match key.rfind('.') {
+            Some(dot) => key = &key[..dot],
+            None => return std::path::PathBuf::new(),
+        }
+    }
+}
+
+/* Merge one fragment into the configuration so far.  Mappings are merged key by key, and lists
+ * (eg dhcp policies) are concatenated, but a setting can only be given a value once.
+ */
+fn merge_yaml(
+    into: &mut Yaml,
+    from: Yaml,
+    key: &str,
+    origin: &std::path::Path,
+    origins: &mut Origins,
+) -> Result<(), Error> {
+    match (into, from) {
+        (Yaml::Hash(into), Yaml::Hash(from)) => {
+            for (k, v) in from {
+                let name = match k.as_str() {
+                    Some(s) if key.is_empty() => s.to_string(),
+                    Some(s) => format!("{}.{}", key, s),
+                    None => format!("{}.{:?}", key, k),
+                };
+                if let Some(existing) = into.get_mut(&k) {
+                    merge_yaml(existing, v, &name, origin, origins)?;
+                } else {
+                    origins.insert(name, origin.to_path_buf());
+                    into.insert(k, v);
+                }
+            }
+            Ok(())
+        }
+        (Yaml::Array(into), Yaml::Array(from)) => {
+            into.extend(from);
+            Ok(())
+        }
+        (_, _) => Err(Error::ConflictingConfig(
+            if key.is_empty() { "Configuration" } else { key }.into(),
+            origin_of(origins, key),
+            origin.to_path_buf(),
+        )),
+    }
+}
+
+/* Removes the include key from a fragment, returning the paths it names.  Relative paths are
+ * relative to the directory of the file they are included from.
+ */
+fn take_includes(
+    fragment: &mut Yaml,
+    path: &std::path::Path,
+) -> Result<Vec<std::path::PathBuf>, Error> {
+    let include = match fragment {
+        Yaml::Hash(h) => h.remove(&Yaml::String("include".into())),
+        _ => None,
+    };
+    let names = match include {
+        None => vec![],
+        Some(Yaml::String(name)) => vec![name],
+        Some(Yaml::Array(names)) => names
+            .into_iter()
+            .map(|name| match name {
+                Yaml::String(name) => Ok(name),
+                e => Err(Error::InvalidInclude(format!("Expected path, got {:?}", e))),
+            })
+            .collect::<Result<_, _>>()?,
+        Some(e) => {
+            return Err(Error::InvalidInclude(format!(
+                "Expected path or list of paths, got {:?}",
+                e
+            )))
+        }
+    };
+    let dir = path.parent().unwrap_or_else(|| std::path::Path::new("."));
+    Ok(names.into_iter().map(|name| dir.join(name)).collect())
+}
+
+/* The configuration fragments in a directory are the *.conf files, in lexical order. */
+fn list_fragments(dir: &std::path::Path) -> Result<Vec<std::path::PathBuf>, Error> {
+    let mut fragments = vec![];
+    for entry in std::fs::read_dir(dir).map_err(Error::IoError)? {
+        let path = entry.map_err(Error::IoError)?.path();
+        if path.extension() == Some(std::ffi::OsStr::new("conf")) {
+            fragments.push(path);
+        }
+    }
+    fragments.sort();
+    Ok(fragments)
+}
+
+/* The configuration can be a single file, or a directory of fragments, and any file can include
+ * further files or directories.  Everything is merged in the order it is found.
+ */
+async fn read_config(path: &std::path::Path) -> Result<Config, Error> {
+    let mut merged = Yaml::Hash(Default::default());
+    let mut origins = Origins::new();
+    let mut seen = std::collections::HashSet::new();
+    let mut pending = vec![path.to_path_buf()];
+    while let Some(path) = pending.pop() {
+        let canonical = std::fs::canonicalize(&path)
+            .map_err(|e| Error::InFile(path.clone(), Box::new(Error::IoError(e))))?;
+        if !seen.insert(canonical.clone()) {
+            return Err(Error::InvalidInclude(format!(
+                "{} is included more than once",
+                path.display()
+            )));
+        }
+        if canonical.is_dir() {
+            pending.extend(list_fragments(&path)?.into_iter().rev());
+            continue;
+        }
+        let mut fragment = match read_fragment(&path)
+            .await
+            .map_err(|e| Error::InFile(path.clone(), Box::new(e)))?
+        {
+            Some(fragment) => fragment,
+            None => continue,
+        };
+        /* Included files are merged straight after the file that includes them */
+        let includes = take_includes(&mut fragment, &path)?;
+        merge_yaml(&mut merged, fragment, "", &path, &mut origins)?;
+        pending.extend(includes.into_iter().rev());
+    }
+    if merged.as_hash().map(|h| h.is_empty()).unwrap_or(true) {
+        return Err(Error::MissingConfig);
+    }
+    build_config(merged)
+}
+
+pub async fn load_config_from_path(path: &std::path::Path) -> Result<SharedConfig, Error> {
+    Ok(std::sync::Arc::new(tokio::sync::Mutex::new(
+        read_config(path).await?,
+    )))
+}
+
+/// Replaces the contents of `conf` with the configuration read from `path`.  If the new
+/// configuration can't be loaded the existing configuration is left untouched.
+pub async fn reload_config(path: &std::path::Path, conf: &SharedConfig) -> Result<(), Error> {
+    /* Load the new configuration completely before taking the lock, so the services never see a
+     * partial configuration, and aren't blocked while a config process runs.
+     */
+    let newconf = read_config(path).await?;
+    let mut lockedconf = conf.lock().await;
+    /* The lease database is only opened at startup */
+    if newconf.dhcp.lease_database != lockedconf.dhcp.lease_database {
+        println!("Changing lease-database requires a restart, still using the previous database");
+    }
+    /* DNS only follows interface changes, the rest of the listen settings are used at startup */
+    if newconf.dns.listen.addresses != lockedconf.dns.listen.addresses
+        || newconf.dns.listen.port != lockedconf.dns.listen.port
+    {
+        println!("Changing the DNS listen addresses or port requires a restart, still using the previous ones");
+    }
+    *lockedconf = newconf;
+    Ok(())
+}
+
+/// Reloads the configuration from `path` whenever we receive a SIGHUP.  The services are
+/// notified through the returned receiver after each successful reload.
+pub fn reload_on_sighup(
+    path: std::path::PathBuf,
+    conf: SharedConfig,
+) -> Result<ConfigReloads, Error> {
+    let mut hup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .map_err(Error::IoError)?;
+    let (notify, reloads) = tokio::sync::watch::channel(());
+    tokio::spawn(async move {
+        while hup.recv().await.is_some() {
+            println!("Received SIGHUP, reloading {}", path.display());
+            match reload_config(&path, &conf).await {
+                Ok(()) => {
+                    println!("Configuration reloaded");
+                    /* This only fails if nothing is interested in reloads */
+                    let _ = notify.broadcast(());
+                }
+                Err(e) => println!("Keeping previous configuration: {}", e),
+            }
+        }
+    });
+    Ok(reloads)
 }
 
 #[test]
 fn test_config_parse() -> Result<(), Error> {
-    load_config_from_string(
+    parse_config(
         "---
 dhcp:
     policies:
@@ -149,3 +372,124 @@ dhcp:
     )?;
     Ok(())
 }
+
+#[test]
+fn test_dns_only_config() -> Result<(), Error> {
+    let conf = parse_config(
+        "---
+dns:
+    forwarders: [192.0.2.53]
+",
+    )?;
+    assert!(conf.dhcp.policies.is_empty());
+    assert_eq!(conf.dns.forwarders.len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_reload_config() -> Result<(), Error> {
+    let dir = crate::test_util::TestDir::new("reload");
+    let path = dir.join("erbium.conf");
+    let write = |policies: &str| {
+        std::fs::write(&path, format!("---\ndhcp:\n    policies:\n{}", policies))
+            .map_err(Error::IoError)
+    };
+    write("      - match-interface: eth0\n")?;
+    let conf = load_config_from_path(&path).await?;
+    assert_eq!(conf.lock().await.dhcp.policies.len(), 1);
+
+    /* A broken configuration leaves the old one in place */
+    write("      - match-interface: [\n")?;
+    assert!(reload_config(&path, &conf).await.is_err());
+    assert_eq!(conf.lock().await.dhcp.policies.len(), 1);
+
+    /* As does one with a value that can't be parsed */
+    write("      - match-interface: eth0\n        apply-renewal-time: h\n")?;
+    assert!(reload_config(&path, &conf).await.is_err());
+    assert_eq!(conf.lock().await.dhcp.policies.len(), 1);
+
+    write("      - match-interface: eth0\n      - match-interface: eth1\n")?;
+    reload_config(&path, &conf).await?;
+    assert_eq!(conf.lock().await.dhcp.policies.len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_reload_on_sighup() -> Result<(), Error> {
+    let dir = crate::test_util::TestDir::new("sighup");
+    let path = dir.join("erbium.conf");
+    let write = |interface: &str| {
+        std::fs::write(
+            &path,
+            format!(
+                "---\ndhcp:\n    policies:\n      - match-interface: {}\n",
+                interface
+            ),
+        )
+        .map_err(Error::IoError)
+    };
+    write("eth0")?;
+    let conf = load_config_from_path(&path).await?;
+    let mut reloads = reload_on_sighup(path.clone(), conf.clone())?;
+    /* The first value is the configuration we started with */
+    reloads.recv().await;
+
+    write("eth1")?;
+    nix::sys::signal::raise(nix::sys::signal::Signal::SIGHUP).expect("Failed to send SIGHUP");
+    let reloaded = tokio::time::timeout(std::time::Duration::from_secs(10), reloads.recv()).await;
+    assert_eq!(reloaded, Ok(Some(())));
+    assert_eq!(
+        conf.lock().await.dhcp.policies[0].match_interface,
+        Some("eth1".into())
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_config_directory() -> Result<(), Error> {
+    let dir = crate::test_util::TestDir::new("fragment");
+    std::fs::create_dir_all(dir.join("vlans")).map_err(Error::IoError)?;
+    let write = |name: &str, contents: &str| {
+        std::fs::write(dir.join(name), contents).map_err(Error::IoError)
+    };
+    write(
+        "10-base.conf",
+        "dhcp:\n    lease-database: ':memory:'\n    policies:\n      - match-interface: eth0\n",
+    )?;
+    write(
+        "20-more.conf",
+        "include: vlans\ndhcp:\n    policies:\n      - match-interface: eth1\n",
+    )?;
+    write(
+        "vlans/vlan10.conf",
+        "dhcp:\n    policies:\n      - match-interface: vlan10\n",
+    )?;
+    write("README", "Not a configuration fragment")?;
+
+    let result = read_config(dir.path()).await;
+    let interfaces = result.map(|conf| {
+        conf.dhcp
+            .policies
+            .iter()
+            .map(|p| p.match_interface.clone().unwrap())
+            .collect::<Vec<_>>()
+    });
+
+    /* Setting the same thing twice is an error that names both files */
+    write(
+        "30-conflict.conf",
+        "dhcp:\n    lease-database: ':memory:'\n",
+    )?;
+    let conflict = read_config(dir.path()).await;
+
+    assert_eq!(interfaces?, vec!["eth0", "eth1", "vlan10"]);
+    match conflict {
+        Err(Error::ConflictingConfig(key, first, second)) => {
+            assert_eq!(key, "dhcp.lease-database");
+            assert_eq!(first, dir.join("10-base.conf"));
+            assert_eq!(second, dir.join("30-conflict.conf"));
+        }
+        e => panic!("Expected a conflict, got {:?}", e),
+    }
+    Ok(())
+}
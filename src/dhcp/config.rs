@@ -83,15 +83,27 @@ fn hexstring(s: &[u8]) -> Result<Vec<u8>, HexError> {
     }
 }
 
-#[derive(Clone, Debug, Default)]
+/// How to check that nobody is already using an address before offering it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Probe {
+    None,
+    Arp,
+    Icmp,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct Policy {
     pub match_interface: Option<String>,
     pub match_chaddr: Option<Vec<u8>>,
     pub match_subnet: Option<crate::net::Ipv4Subnet>,
+    pub match_circuit_id: Option<Vec<u8>>,
+    pub match_remote_id: Option<Vec<u8>>,
     pub match_other: std::collections::HashMap<dhcppkt::DhcpOption, dhcppkt::DhcpOptionTypeValue>,
     pub apply_address: Option<super::pool::PoolAddresses>,
     pub apply_default_lease: Option<std::time::Duration>,
     pub apply_max_lease: Option<std::time::Duration>,
+    pub apply_probe: Option<Probe>,
+    pub apply_probe_timeout: Option<std::time::Duration>,
     pub apply_other: std::collections::HashMap<dhcppkt::DhcpOption, dhcppkt::DhcpOptionTypeValue>,
     pub policies: Vec<Policy>,
 }
@@ -110,9 +122,46 @@ impl Policy {
     }
 }
 
-#[derive(Debug)]
+/// How a reservation identifies the client it is for.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ReservationKey {
+    HardwareAddress(Vec<u8>),
+    ClientId(Vec<u8>),
+    HostName(String),
+}
+
+impl std::fmt::Display for ReservationKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let hex = |v: &Vec<u8>| {
+            v.iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<Vec<String>>()
+                .join(":")
+        };
+        match self {
+            ReservationKey::HardwareAddress(h) => write!(f, "hardware-address {}", hex(h)),
+            ReservationKey::ClientId(c) => write!(f, "client-id {}", hex(c)),
+            ReservationKey::HostName(n) => write!(f, "host-name {}", n),
+        }
+    }
+}
+
+/// An address that is only ever given to one client.
+#[derive(Clone, Debug)]
+pub struct Reservation {
+    pub key: ReservationKey,
+    pub address: std::net::Ipv4Addr,
+}
+
+#[derive(Debug, Default)]
 pub struct Config {
     pub policies: Vec<Policy>,
+    pub reservations: Vec<Reservation>,
+    pub decline_quarantine: Option<std::time::Duration>,
+    pub lease_database: Option<std::path::PathBuf>,
+    pub lease_history: Option<usize>,
+    pub lease_script: Option<std::path::PathBuf>,
+    pub lease_script_timeout: Option<std::time::Duration>,
 }
 
 impl Config {
@@ -195,41 +244,44 @@ impl Config {
 
     fn parse_duration(value: &yaml::Yaml) -> Result<std::time::Duration, Error> {
         if let Some(v) = value.as_str() {
-            let mut num = None;
+            let mut num: Option<u64> = None;
             let mut ret = Default::default();
-            for c in v.chars() {
-                match c {
+            let mut chars = v.chars().peekable();
+            let too_long = || Error::InvalidConfig(format!("Duration {} is too long", v));
+            while let Some(c) = chars.next() {
+                /* How many milliseconds each unit is */
+                let unit = match c {
                     '0'..='9' => {
-                        if let Some(n) = num {
-                            num = Some(n * 10 + c as u64 - '0' as u64);
-                        } else {
-                            num = Some(c as u64 - '0' as u64);
-                        }
-                    }
-                    's' => {
-                        ret += std::time::Duration::from_secs(num.take().unwrap());
-                    }
-                    'm' => {
-                        ret += std::time::Duration::from_secs(num.take().unwrap() * 60);
-                    }
-                    'h' => {
-                        ret += std::time::Duration::from_secs(num.take().unwrap() * 3600);
-                    }
-                    'd' => {
-                        ret += std::time::Duration::from_secs(num.take().unwrap() * 86400);
+                        num = Some(
+                            num.unwrap_or(0)
+                                .checked_mul(10)
+                                .and_then(|n| n.checked_add(c as u64 - '0' as u64))
+                                .ok_or_else(too_long)?,
+                        );
+                        continue;
                     }
-                    'w' => {
-                        ret += std::time::Duration::from_secs(num.take().unwrap() * 7 * 86400);
+                    's' => 1000,
+                    'm' if chars.peek() == Some(&'s') => {
+                        chars.next();
+                        1
                     }
-                    x if x.is_whitespace() => (),
-                    '_' => (),
+                    'm' => 60 * 1000,
+                    'h' => 3600 * 1000,
+                    'd' => 86400 * 1000,
+                    'w' => 7 * 86400 * 1000,
+                    x if x.is_whitespace() => continue,
+                    '_' => continue,
                     _ => {
                         return Err(Error::InvalidConfig(format!(
                             "Unexpected {} in duration",
                             c
                         )))
                     }
-                }
+                };
+                let n = num.take().ok_or_else(|| {
+                    Error::InvalidConfig(format!("Missing number before {} in duration", c))
+                })?;
+                ret += std::time::Duration::from_millis(n.checked_mul(unit).ok_or_else(too_long)?);
             }
             if let Some(n) = num {
                 ret += std::time::Duration::from_secs(n);
@@ -353,6 +405,30 @@ impl Config {
                                 .map_err(|x| x.annotate("Failed to parse match-subnet"))?,
                         );
                     }
+                    Some("match-circuit-id") => {
+                        if policy.match_circuit_id.is_some() {
+                            return Err(Error::InvalidConfig(
+                                "match-circuit-id specified twice".into(),
+                            ));
+                        }
+                        policy.match_circuit_id = Some(
+                            Config::parse_string(v)
+                                .map_err(|x| x.annotate("Failed to parse match-circuit-id"))?
+                                .into_bytes(),
+                        );
+                    }
+                    Some("match-remote-id") => {
+                        if policy.match_remote_id.is_some() {
+                            return Err(Error::InvalidConfig(
+                                "match-remote-id specified twice".into(),
+                            ));
+                        }
+                        policy.match_remote_id = Some(
+                            Config::parse_string(v)
+                                .map_err(|x| x.annotate("Failed to parse match-remote-id"))?
+                                .into_bytes(),
+                        );
+                    }
                     Some(x) if x.starts_with("match-") => {
                         let name = &x[6..];
                         let (opt, value) = Config::parse_generic(name, v)
@@ -373,11 +449,35 @@ impl Config {
                         );
                     }
                     Some("apply-max-lease") => {
-                        policy.apply_default_lease = Some(
+                        policy.apply_max_lease = Some(
                             Config::parse_duration(v)
                                 .map_err(|x| x.annotate("Failed to parse apply-max-lease"))?,
                         );
                     }
+                    Some("apply-probe") => {
+                        policy.apply_probe = Some(
+                            match Config::parse_string(v)
+                                .map_err(|x| x.annotate("Failed to parse apply-probe"))?
+                                .as_str()
+                            {
+                                "none" => Probe::None,
+                                "arp" => Probe::Arp,
+                                "icmp" => Probe::Icmp,
+                                x => {
+                                    return Err(Error::InvalidConfig(format!(
+                                        "Unknown probe method {}, expected arp, icmp or none",
+                                        x
+                                    )))
+                                }
+                            },
+                        );
+                    }
+                    Some("apply-probe-timeout") => {
+                        policy.apply_probe_timeout = Some(
+                            Config::parse_duration(v)
+                                .map_err(|x| x.annotate("Failed to parse apply-probe-timeout"))?,
+                        );
+                    }
                     Some("apply-range") => {
                         if let Some(range) = v.as_hash() {
                             let mut start: Option<std::net::Ipv4Addr> = None;
@@ -479,6 +579,88 @@ impl Config {
         }
     }
 
+    fn parse_reservation(fragment: &yaml::Yaml) -> Result<Reservation, Error> {
+        let h = fragment
+            .as_hash()
+            .ok_or_else(|| Error::InvalidConfig("Reservation should be a hash".into()))?;
+        let mut key = None;
+        let mut address = None;
+        for (k, v) in h {
+            let newkey = match k.as_str() {
+                Some("address") => {
+                    address = Some(
+                        Config::parse_ip(v).map_err(|x| x.annotate("Failed to parse address"))?,
+                    );
+                    continue;
+                }
+                Some("hardware-address") => ReservationKey::HardwareAddress(
+                    Config::parse_mac(v)
+                        .map_err(|x| x.annotate("Failed to parse hardware-address"))?,
+                ),
+                Some("client-id") => ReservationKey::ClientId(
+                    Config::parse_mac(v).map_err(|x| x.annotate("Failed to parse client-id"))?,
+                ),
+                Some("host-name") => ReservationKey::HostName(
+                    Config::parse_string(v).map_err(|x| x.annotate("Failed to parse host-name"))?,
+                ),
+                Some(x) => {
+                    return Err(Error::InvalidConfig(format!(
+                        "Reservation contains unknown field '{}'",
+                        x
+                    )))
+                }
+                None => {
+                    return Err(Error::InvalidConfig(format!(
+                        "Unexpected key {:?} in reservation",
+                        k
+                    )))
+                }
+            };
+            if key.is_some() {
+                return Err(Error::InvalidConfig(
+                    "Reservation should only have one of hardware-address, client-id or host-name"
+                        .into(),
+                ));
+            }
+            key = Some(newkey);
+        }
+        Ok(Reservation {
+            key: key.ok_or_else(|| {
+                Error::InvalidConfig(
+                    "Reservation needs one of hardware-address, client-id or host-name".into(),
+                )
+            })?,
+            address: address
+                .ok_or_else(|| Error::InvalidConfig("Reservation is missing address".into()))?,
+        })
+    }
+
+    fn parse_reservations(fragment: &yaml::Yaml) -> Result<Vec<Reservation>, Error> {
+        let l = fragment.as_vec().ok_or_else(|| {
+            Error::InvalidConfig("reservations should be a list of reservations".into())
+        })?;
+        let mut reservations: Vec<Reservation> = Vec::new();
+        let mut keys = std::collections::HashMap::new();
+        let mut addresses = std::collections::HashMap::new();
+        for i in l {
+            let reservation = Config::parse_reservation(i)?;
+            if let Some(other) = keys.insert(reservation.key.clone(), reservation.address) {
+                return Err(Error::InvalidConfig(format!(
+                    "Duplicate reservation for {}: {} and {}",
+                    reservation.key, other, reservation.address
+                )));
+            }
+            if let Some(other) = addresses.insert(reservation.address, reservation.key.clone()) {
+                return Err(Error::InvalidConfig(format!(
+                    "Address {} is reserved for both {} and {}",
+                    reservation.address, other, reservation.key
+                )));
+            }
+            reservations.push(reservation);
+        }
+        Ok(reservations)
+    }
+
     fn parse_policies(fragment: &yaml::Yaml) -> Result<Vec<Policy>, Error> {
         if let Some(l) = fragment.as_vec() {
             let mut policies = Vec::new();
@@ -493,12 +675,53 @@ impl Config {
         }
     }
 
-    fn parse_dhcp(fragment: &yaml::Yaml) -> Result<Vec<Policy>, Error> {
+    fn parse_dhcp(fragment: &yaml::Yaml) -> Result<Config, Error> {
         if let Some(h) = fragment.as_hash() {
-            let mut policies = Vec::new();
+            let mut conf: Config = Default::default();
             for (k, v) in h {
                 match k.as_str() {
-                    Some("policies") => policies = Config::parse_policies(v)?,
+                    Some("policies") => conf.policies = Config::parse_policies(v)?,
+                    Some("reservations") => {
+                        conf.reservations = Config::parse_reservations(v)
+                            .map_err(|x| x.annotate("Failed to parse reservations"))?
+                    }
+                    Some("decline-quarantine") => {
+                        conf.decline_quarantine = Some(
+                            Config::parse_duration(v)
+                                .map_err(|x| x.annotate("Failed to parse decline-quarantine"))?,
+                        )
+                    }
+                    Some("lease-database") => {
+                        conf.lease_database = Some(
+                            Config::parse_string(v)
+                                .map_err(|x| x.annotate("Failed to parse lease-database"))?
+                                .into(),
+                        )
+                    }
+                    Some("lease-script") => {
+                        conf.lease_script = Some(
+                            Config::parse_string(v)
+                                .map_err(|x| x.annotate("Failed to parse lease-script"))?
+                                .into(),
+                        )
+                    }
+                    Some("lease-script-timeout") => {
+                        conf.lease_script_timeout = Some(
+                            Config::parse_duration(v)
+                                .map_err(|x| x.annotate("Failed to parse lease-script-timeout"))?,
+                        )
+                    }
+                    Some("lease-history") => {
+                        conf.lease_history = Some(
+                            usize::try_from(
+                                Config::parse_number(v)
+                                    .map_err(|x| x.annotate("Failed to parse lease-history"))?,
+                            )
+                            .map_err(|_| {
+                                Error::InvalidConfig("lease-history cannot be negative".into())
+                            })?,
+                        )
+                    }
                     Some(x) => {
                         return Err(Error::InvalidConfig(format!(
                             "Unexpected item {} in dhcp fragment",
@@ -513,7 +736,7 @@ impl Config {
                     }
                 }
             }
-            Ok(policies)
+            Ok(conf)
         } else {
             Err(Error::InvalidConfig("dhcp is expected to be a hash".into()))
         }
@@ -524,10 +747,10 @@ impl Config {
             .as_hash()
             .and_then(|h| h.get(&yaml::Yaml::from_str("dhcp")))
         {
-            let policies = Config::parse_dhcp(dhcpconf)?;
-            Ok(Config { policies })
+            Config::parse_dhcp(dhcpconf)
         } else {
-            Err(Error::InvalidConfig("Missing dhcp section".into()))
+            /* A configuration that only runs DNS doesn't need a dhcp section */
+            Ok(Default::default())
         }
     }
 }
@@ -542,4 +765,55 @@ fn test_duration() {
         Config::parse_duration(&yaml::Yaml::String("1w2d3h4m5s".into())).unwrap(),
         std::time::Duration::from_secs(7 * 86400 + 2 * 86400 + 3 * 3600 + 4 * 60 + 5)
     );
+    assert_eq!(
+        Config::parse_duration(&yaml::Yaml::String("1m500ms".into())).unwrap(),
+        std::time::Duration::from_millis(60_500)
+    );
+    for bad in &["h", "m", "ms", "5mh", "1x", "99999999999999999999s"] {
+        assert!(Config::parse_duration(&yaml::Yaml::String((*bad).into())).is_err());
+    }
+}
+
+#[test]
+fn test_duplicate_reservations() {
+    let parse =
+        |s: &str| Config::parse_reservations(&yaml_rust::YamlLoader::load_from_str(s).unwrap()[0]);
+    let reservations = parse(
+        "
+- { hardware-address: 00:00:5E:00:53:01, address: 192.0.2.10 }
+- { client-id: 01:00:00:5E:00:53:01, address: 192.0.2.11 }
+- { host-name: printer, address: 192.0.2.12 }
+",
+    )
+    .expect("Failed to parse reservations");
+    assert_eq!(reservations.len(), 3);
+    assert_eq!(
+        reservations[2].key,
+        ReservationKey::HostName("printer".into())
+    );
+
+    /* The same address reserved twice */
+    assert!(parse(
+        "
+- { hardware-address: 00:00:5E:00:53:01, address: 192.0.2.10 }
+- { host-name: printer, address: 192.0.2.10 }
+"
+    )
+    .is_err());
+
+    /* The same client reserved twice */
+    assert!(parse(
+        "
+- { host-name: printer, address: 192.0.2.10 }
+- { host-name: printer, address: 192.0.2.11 }
+"
+    )
+    .is_err());
+
+    /* Ambiguous reservations */
+    assert!(parse(
+        "- { host-name: printer, hardware-address: 00:00:5E:00:53:01, address: 192.0.2.10 }"
+    )
+    .is_err());
+    assert!(parse("- { address: 192.0.2.10 }").is_err());
 }
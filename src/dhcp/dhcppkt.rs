@@ -121,6 +121,9 @@ impl fmt::Debug for HwType {
     }
 }
 
+/* RFC2131 Section 2: The leftmost bit of the 'flags' field is the BROADCAST (B) flag */
+pub const FLAG_BROADCAST: u16 = 0x8000;
+
 #[derive(Copy, Clone, PartialEq, Eq)]
 pub struct MessageType(u8);
 pub const DHCPDISCOVER: MessageType = MessageType(1);
@@ -230,14 +233,23 @@ pub const OPTION_STREETTALK: DhcpOption = DhcpOption(75);
 pub const OPTION_STDA: DhcpOption = DhcpOption(76);
 pub const OPTION_USERCLASS: DhcpOption = DhcpOption(77); /* RFC3004 */
 pub const OPTION_FQDN: DhcpOption = DhcpOption(81); /* RFC4702 */
+pub const OPTION_RELAYINFO: DhcpOption = DhcpOption(82); /* RFC3046 */
+pub const OPTION_AUTHENTICATION: DhcpOption = DhcpOption(90); /* RFC3118 */
 pub const OPTION_PCODE: DhcpOption = DhcpOption(100); /* RFC4833 */
 pub const OPTION_TCODE: DhcpOption = DhcpOption(101); /* RFC4833 */
 pub const OPTION_AUTOCONF: DhcpOption = DhcpOption(103);
-pub const OPTION_SUBNETSELECT: DhcpOption = DhcpOption(104);
+pub const OPTION_SUBNETSELECT: DhcpOption = DhcpOption(118); /* RFC3011 */
 //pub const OPTION_DOMAINSEARCH: DhcpOption = DhcpOption(119);
 //pub const OPTION_CIDRROUTE: DhcpOption = DhcpOption(121);
+pub const OPTION_FORCERENEW_NONCE_CAPABLE: DhcpOption = DhcpOption(145); /* RFC6704 */
 pub const OPTION_CAPTIVEPORTAL: DhcpOption = DhcpOption(160);
 
+/* Sub-options of OPTION_RELAYINFO */
+pub const RELAYINFO_CIRCUITID: u8 = 1; /* RFC3046 */
+pub const RELAYINFO_REMOTEID: u8 = 2; /* RFC3046 */
+pub const RELAYINFO_LINKSELECTION: u8 = 5; /* RFC3527 */
+pub const RELAYINFO_SUBSCRIBERID: u8 = 6; /* RFC3993 */
+
 const OPT_INFO: &[(&str, DhcpOption, DhcpOptionType)] = &[
     ("netmask", OPTION_NETMASK, DhcpOptionType::Ip),
     ("time-offset", OPTION_TIMEOFFSET, DhcpOptionType::I32),
@@ -336,9 +348,9 @@ const OPT_INFO: &[(&str, DhcpOption, DhcpOptionType)] = &[
     (
         "renewal-time",
         OPTION_RENEWALTIME,
-        DhcpOptionType::Seconds16,
-    ), // seconds
-    ("rebind-time", OPTION_REBINDTIME, DhcpOptionType::Seconds16), // seconds
+        DhcpOptionType::Seconds32,
+    ),
+    ("rebind-time", OPTION_REBINDTIME, DhcpOptionType::Seconds32),
     // 60
     ("class-id", OPTION_VENDOR_CLASS, DhcpOptionType::String),
     ("client-id", OPTION_CLIENTID, DhcpOptionType::HwAddr),
@@ -399,7 +411,7 @@ const OPT_INFO: &[(&str, DhcpOption, DhcpOptionType)] = &[
     ("tz-name", OPTION_TCODE, DhcpOptionType::String),
     // uuid/guid
     ("autoconfig", OPTION_AUTOCONF, DhcpOptionType::Bool),
-    ("subnet-selection", OPTION_SUBNETSELECT, DhcpOptionType::Ip), // RFC3011 -- needs better support
+    ("subnet-selection", OPTION_SUBNETSELECT, DhcpOptionType::Ip), // RFC3011
     //("search-path", OPTION_DNSSEARCH, DhcpOptionType::...), // RFC3397
     //("sip-servers", OPTION_SIPSERVERS, DhcpOptionType::...), // RFC3361
     //121: classless routes, RFC3442 -- Don't intend to implement.
@@ -457,7 +469,7 @@ impl DhcpOptionType {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum DhcpOptionTypeValue {
     String(String),
     IpList(Vec<std::net::Ipv4Addr>),
@@ -669,6 +681,47 @@ impl DhcpParse for MessageType {
     }
 }
 
+/// The decoded contents of the Relay Agent Information option (option 82).
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct RelayAgentInformation {
+    /// Identifies the circuit (eg switch port) the request was received on.
+    pub circuit_id: Option<Vec<u8>>,
+    /// Identifies the remote host end of the circuit (eg a modem).
+    pub remote_id: Option<Vec<u8>>,
+    /// The link the client should be given an address on.
+    pub link_selection: Option<std::net::Ipv4Addr>,
+    /// An administrative identifier for the subscriber.
+    pub subscriber_id: Option<Vec<u8>>,
+}
+
+/* RFC3046 Section 2.0: The Agent Information field consists of a sequence of SubOpt/Length/Value
+ * tuples for each sub-option
+ */
+impl DhcpParse for RelayAgentInformation {
+    type Item = Self;
+    fn parse_into(v: &[u8]) -> Option<Self> {
+        let mut info: RelayAgentInformation = Default::default();
+        let mut it = v.iter().copied();
+        while let Some(code) = it.next() {
+            let len = it.next()? as usize;
+            let value: Vec<u8> = it.by_ref().take(len).collect();
+            if value.len() != len {
+                return None;
+            }
+            match code {
+                RELAYINFO_CIRCUITID => info.circuit_id = Some(value),
+                RELAYINFO_REMOTEID => info.remote_id = Some(value),
+                RELAYINFO_LINKSELECTION => {
+                    info.link_selection = std::net::Ipv4Addr::parse_into(&value)
+                }
+                RELAYINFO_SUBSCRIBERID => info.subscriber_id = Some(value),
+                _ => (), /* Unknown sub-options are ignored */
+            }
+        }
+        Some(info)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Default)]
 pub struct DhcpOptions {
     pub other: collections::HashMap<DhcpOption, Vec<u8>>,
@@ -699,6 +752,10 @@ impl DhcpOptions {
         self.get_option::<MessageType>(&OPTION_MSGTYPE)
     }
 
+    pub fn get_relay_info(&self) -> Option<RelayAgentInformation> {
+        self.get_option::<RelayAgentInformation>(&OPTION_RELAYINFO)
+    }
+
     pub fn set_raw_option(mut self, option: &DhcpOption, value: &[u8]) -> Self {
         self.other.insert(*option, value.to_vec());
         self
@@ -967,7 +1024,16 @@ where
 impl Serialise for DhcpOptions {
     fn serialise(&self, v: &mut Vec<u8>) {
         for (o, p) in self.other.iter() {
-            serialise_option(*o, p, v);
+            if *o != OPTION_RELAYINFO {
+                serialise_option(*o, p, v);
+            }
+        }
+
+        /* RFC3046 Section 2.2: The DHCP server ... MUST copy the Relay Agent Information option as
+         * the last DHCP option in the response.
+         */
+        if let Some(p) = self.other.get(&OPTION_RELAYINFO) {
+            serialise_option(OPTION_RELAYINFO, p, v);
         }
 
         /* Add end of options marker */
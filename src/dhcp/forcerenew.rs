@@ -0,0 +1,279 @@
+/*   Copyright 2020 Perry Lorier
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *  SPDX-License-Identifier: Apache-2.0
+ *
+ *  Sections quoted from RFCs are covered by the terms specified in RFC3978.
+ *
+ *  DHCPFORCERENEW (RFC3203) with Forcerenew Nonce Authentication (RFC6704).
+ */
+
+use super::config;
+use super::dhcppkt;
+use super::pool;
+use rand::Rng;
+use std::collections;
+use std::net;
+
+/* RFC6704 Section 4: Forcerenew Nonce Authentication uses protocol 3, algorithm 1 (HMAC-MD5) and
+ * replay detection method 0 (a monotonically increasing counter).
+ */
+const PROTOCOL_FORCERENEW_NONCE: u8 = 3;
+const ALGORITHM_HMAC_MD5: u8 = 1;
+const RDM_MONOTONIC: u8 = 0;
+const AUTHINFO_NONCE_VALUE: u8 = 1;
+const AUTHINFO_HMAC_MD5_DIGEST: u8 = 2;
+const NONCE_LEN: usize = 16;
+/* Protocol, algorithm, RDM, replay detection, and the auth info type */
+const AUTH_HEADER_LEN: usize = 1 + 1 + 1 + 8 + 1;
+
+/* RFC6704 Section 3.3.1: A DHCPv4 client indicates DHCP Forcerenew Nonce Protocol support by
+ * including the FORCERENEW_NONCE_CAPABLE option in the DHCPDISCOVER and DHCPREQUEST messages.
+ */
+pub fn is_nonce_capable(pkt: &dhcppkt::DHCP) -> bool {
+    pkt.options
+        .get_raw_option(&dhcppkt::OPTION_FORCERENEW_NONCE_CAPABLE)
+        .map(|algorithms| algorithms.contains(&ALGORITHM_HMAC_MD5))
+        .unwrap_or(false)
+}
+
+pub fn mk_nonce() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    (0..NONCE_LEN).map(|_| rng.gen()).collect()
+}
+
+/* RFC3118 Section 2: The replay detection field MUST be set to a value that is monotonically
+ * increasing, so use the current time in nanoseconds.
+ */
+fn replay_detection() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .expect("clock failure")
+        .as_nanos() as u64
+}
+
+fn mk_auth(info_type: u8, value: &[u8]) -> Vec<u8> {
+    let mut auth = vec![PROTOCOL_FORCERENEW_NONCE, ALGORITHM_HMAC_MD5, RDM_MONOTONIC];
+    auth.extend_from_slice(&replay_detection().to_be_bytes());
+    auth.push(info_type);
+    auth.extend_from_slice(value);
+    auth
+}
+
+/* RFC6704 Section 3.3.1: The server ... MUST include the Forcerenew nonce in the Authentication
+ * option in the DHCPACK message.
+ */
+pub fn mk_nonce_auth(nonce: &[u8]) -> Vec<u8> {
+    mk_auth(AUTHINFO_NONCE_VALUE, nonce)
+}
+
+/* RFC2104 */
+fn hmac_md5(key: &[u8], msg: &[u8]) -> [u8; 16] {
+    const BLOCK_LEN: usize = 64;
+    let mut k = [0u8; BLOCK_LEN];
+    if key.len() > BLOCK_LEN {
+        k[..16].copy_from_slice(&md5::compute(key).0);
+    } else {
+        k[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = md5::Context::new();
+    inner.consume(k.iter().map(|b| b ^ 0x36).collect::<Vec<u8>>());
+    inner.consume(msg);
+    let mut outer = md5::Context::new();
+    outer.consume(k.iter().map(|b| b ^ 0x5c).collect::<Vec<u8>>());
+    outer.consume(inner.compute().0);
+    outer.compute().0
+}
+
+/* Finds where the digest lives inside the Authentication option of a serialised packet */
+fn find_digest(buf: &[u8]) -> Option<std::ops::Range<usize>> {
+    let mut i = 240; /* Fixed header plus the magic cookie */
+    while i < buf.len() {
+        match buf[i] {
+            0 => i += 1,
+            255 => return None,
+            code => {
+                let len = *buf.get(i + 1)? as usize;
+                if dhcppkt::DhcpOption::from(code) == dhcppkt::OPTION_AUTHENTICATION
+                    && len == AUTH_HEADER_LEN + 16
+                {
+                    let start = i + 2 + AUTH_HEADER_LEN;
+                    return Some(start..start + 16);
+                }
+                i += 2 + len;
+            }
+        }
+    }
+    None
+}
+
+/* RFC6704 Section 3.3.2: The server computes the HMAC-MD5 over the entire DHCP message, with the
+ * Authentication Information field set to zero, using the Forcerenew nonce as the key.
+ */
+fn sign(buf: &mut [u8], key: &[u8]) {
+    let digest = find_digest(buf).expect("Signing message without an authentication option");
+    buf[digest.clone()].copy_from_slice(&[0; 16]);
+    let hmac = hmac_md5(key, buf);
+    buf[digest].copy_from_slice(&hmac);
+}
+
+/// Builds a signed DHCPFORCERENEW to send to the client holding `lease`.
+pub fn mk_forcerenew(lease: &pool::ForceRenewInfo) -> Vec<u8> {
+    let pkt = dhcppkt::DHCP {
+        op: dhcppkt::OP_BOOTREPLY,
+        htype: dhcppkt::HWTYPE_ETHERNET,
+        hlen: lease.chaddr.len() as u8,
+        hops: 0,
+        xid: rand::thread_rng().gen(),
+        secs: 0,
+        flags: 0,
+        ciaddr: lease.address,
+        yiaddr: net::Ipv4Addr::UNSPECIFIED,
+        siaddr: net::Ipv4Addr::UNSPECIFIED,
+        giaddr: net::Ipv4Addr::UNSPECIFIED,
+        chaddr: lease.chaddr.clone(),
+        sname: vec![],
+        file: vec![],
+        options: dhcppkt::DhcpOptions {
+            other: collections::HashMap::new(),
+        }
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPFORCERENEW)
+        .set_option(&dhcppkt::OPTION_SERVERID, &lease.serverid)
+        .set_raw_option(
+            &dhcppkt::OPTION_AUTHENTICATION,
+            &mk_auth(AUTHINFO_HMAC_MD5_DIGEST, &[0; 16]),
+        ),
+    };
+    let mut buf = pkt.serialise();
+    sign(&mut buf, &lease.nonce);
+    buf
+}
+
+/// How the policies changed between two configurations, as paths into the policy lists.  Leases
+/// record the path of the policy they were given under (see get_client_info).
+#[derive(Debug, Default, PartialEq)]
+pub struct PolicyChanges {
+    /// Policies (as paths in the old configuration) whose clients need to renew.
+    pub changed: Vec<String>,
+    /// Policies that are unchanged, but are somewhere else in the new configuration, as
+    /// (old path, new path).
+    pub moved: Vec<(String, String)>,
+}
+
+fn join_path(prefix: &str, i: usize) -> String {
+    if prefix.is_empty() {
+        i.to_string()
+    } else {
+        format!("{}.{}", prefix, i)
+    }
+}
+
+fn moved_subtree(policy: &config::Policy, old: &str, new: &str, moved: &mut Vec<(String, String)>) {
+    if old != new {
+        moved.push((old.into(), new.into()));
+    }
+    for (i, p) in policy.policies.iter().enumerate() {
+        moved_subtree(p, &join_path(old, i), &join_path(new, i), moved);
+    }
+}
+
+fn compare_policies(
+    old: &[config::Policy],
+    new: &[config::Policy],
+    old_prefix: &str,
+    new_prefix: &str,
+    changes: &mut PolicyChanges,
+) {
+    let without_subpolicies = |p: &config::Policy| config::Policy {
+        policies: vec![],
+        ..p.clone()
+    };
+    for (i, o) in old.iter().enumerate() {
+        let old_path = join_path(old_prefix, i);
+        if let Some(j) = new.iter().position(|n| n == o) {
+            moved_subtree(o, &old_path, &join_path(new_prefix, j), &mut changes.moved);
+            continue;
+        }
+        /* A change to a policy affects all of its subpolicies, but if only some of its
+         * subpolicies changed, only their clients are affected.
+         */
+        let outer = without_subpolicies(o);
+        match new.iter().position(|n| without_subpolicies(n) == outer) {
+            Some(j) => {
+                let new_path = join_path(new_prefix, j);
+                if old_path != new_path {
+                    changes.moved.push((old_path.clone(), new_path.clone()));
+                }
+                compare_policies(&o.policies, &new[j].policies, &old_path, &new_path, changes);
+            }
+            None => changes.changed.push(old_path),
+        }
+    }
+}
+
+/// Compares the policies from two configurations.  Policies are compared by what they contain
+/// rather than where they are, so adding, removing or moving policies doesn't disturb clients on
+/// other policies.  Policies that were removed count as changed, so their clients come back and
+/// find a new policy.
+pub fn changed_policies(old: &[config::Policy], new: &[config::Policy]) -> PolicyChanges {
+    let mut changes: PolicyChanges = Default::default();
+    compare_policies(old, new, "", "", &mut changes);
+    changes
+}
+
+#[test]
+fn test_hmac_md5() {
+    /* RFC2104 Appendix test vectors */
+    assert_eq!(
+        hmac_md5(&[0x0b; 16], b"Hi There"),
+        [
+            0x92, 0x94, 0x72, 0x7a, 0x36, 0x38, 0xbb, 0x1c, 0x13, 0xf4, 0x8e, 0xf8, 0x15, 0x8b,
+            0xfc, 0x9d
+        ]
+    );
+    assert_eq!(
+        hmac_md5(b"Jefe", b"what do ya want for nothing?"),
+        [
+            0x75, 0x0c, 0x78, 0x3e, 0x6a, 0xb0, 0xb5, 0x03, 0xea, 0xa8, 0x6e, 0x31, 0x0a, 0x5d,
+            0xb7, 0x38
+        ]
+    );
+}
+
+#[test]
+fn test_forcerenew_signature() {
+    let lease = pool::ForceRenewInfo {
+        address: "192.0.2.10".parse().unwrap(),
+        chaddr: vec![0x00, 0x00, 0x5E, 0x00, 0x53, 0x01],
+        serverid: "192.0.2.1".parse().unwrap(),
+        nonce: mk_nonce(),
+    };
+    let mut buf = mk_forcerenew(&lease);
+    let pkt = dhcppkt::parse(&buf).expect("Failed to parse DHCPFORCERENEW");
+    assert_eq!(pkt.options.get_messagetype(), Some(dhcppkt::DHCPFORCERENEW));
+    assert_eq!(pkt.options.get_serverid(), Some(lease.serverid));
+    let auth = pkt
+        .options
+        .get_raw_option(&dhcppkt::OPTION_AUTHENTICATION)
+        .expect("No authentication option");
+    assert_eq!(auth[..3], [3, 1, 0]);
+    assert_eq!(auth[11], AUTHINFO_HMAC_MD5_DIGEST);
+
+    /* The client checks the digest by zeroing it, and computing the HMAC itself */
+    let digest = find_digest(&buf).expect("No digest");
+    let sent = buf[digest.clone()].to_vec();
+    buf[digest].copy_from_slice(&[0; 16]);
+    assert_eq!(hmac_md5(&lease.nonce, &buf).to_vec(), sent);
+    assert_ne!(hmac_md5(&mk_nonce(), &buf).to_vec(), sent);
+}
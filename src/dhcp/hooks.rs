@@ -0,0 +1,211 @@
+/*   Copyright 2020 Perry Lorier
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *  SPDX-License-Identifier: Apache-2.0
+ *
+ *  Runs an external script when leases change.
+ */
+
+use super::config;
+use super::dhcppkt;
+use super::pool;
+use std::net;
+
+pub const DEFAULT_SCRIPT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LeaseEvent {
+    Granted,
+    Renewed,
+    Released,
+    Expired,
+}
+
+impl std::fmt::Display for LeaseEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LeaseEvent::Granted => write!(f, "granted"),
+            LeaseEvent::Renewed => write!(f, "renewed"),
+            LeaseEvent::Released => write!(f, "released"),
+            LeaseEvent::Expired => write!(f, "expired"),
+        }
+    }
+}
+
+/// What the script is told about the lease.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LeaseDetails {
+    pub address: Option<net::Ipv4Addr>,
+    pub chaddr: Option<Vec<u8>>,
+    pub clientid: Option<Vec<u8>>,
+    pub hostname: Option<String>,
+    pub interface: Option<String>,
+}
+
+fn hex(v: &[u8], sep: &str) -> String {
+    v.iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<String>>()
+        .join(sep)
+}
+
+impl LeaseDetails {
+    pub fn from_request(req: &super::DHCPRequest, address: net::Ipv4Addr) -> Self {
+        LeaseDetails {
+            address: Some(address),
+            chaddr: Some(req.pkt.chaddr.clone()),
+            clientid: req.pkt.options.get_clientid(),
+            hostname: req
+                .pkt
+                .options
+                .get_raw_option(&dhcppkt::OPTION_HOSTNAME)
+                .map(|h| String::from_utf8_lossy(h).into_owned()),
+            interface: req.ifname.clone(),
+        }
+    }
+
+    pub fn from_lease(lease: &pool::LeaseInfo) -> Self {
+        LeaseDetails {
+            address: Some(lease.address),
+            chaddr: lease.client.chaddr.clone(),
+            clientid: lease.clientid.clone(),
+            hostname: lease.client.hostname.clone(),
+            interface: None,
+        }
+    }
+
+    /* Only the details we know are set, so scripts can tell "unknown" from "empty" */
+    fn environment(&self) -> Vec<(&'static str, String)> {
+        let mut env = vec![];
+        if let Some(address) = self.address {
+            env.push(("ERBIUM_ADDRESS", address.to_string()));
+        }
+        if let Some(chaddr) = &self.chaddr {
+            env.push(("ERBIUM_MAC", hex(chaddr, ":")));
+        }
+        if let Some(clientid) = &self.clientid {
+            env.push(("ERBIUM_CLIENT_ID", hex(clientid, "")));
+        }
+        if let Some(hostname) = &self.hostname {
+            env.push(("ERBIUM_HOSTNAME", hostname.clone()));
+        }
+        if let Some(interface) = &self.interface {
+            env.push(("ERBIUM_INTERFACE", interface.clone()));
+        }
+        env
+    }
+}
+
+async fn run(
+    script: std::path::PathBuf,
+    timeout: std::time::Duration,
+    event: LeaseEvent,
+    details: LeaseDetails,
+) {
+    let child = tokio::process::Command::new(&script)
+        .arg(event.to_string())
+        .envs(details.environment())
+        .stdin(std::process::Stdio::null())
+        .kill_on_drop(true)
+        .spawn();
+    let child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            println!("Failed to run lease script {}: {}", script.display(), e);
+            return;
+        }
+    };
+    /* If it takes too long, the child is dropped, and thus killed */
+    match tokio::time::timeout(timeout, child).await {
+        Err(_) => println!(
+            "Lease script {} timed out after {}s",
+            script.display(),
+            timeout.as_secs()
+        ),
+        Ok(Err(e)) => println!("Failed to run lease script {}: {}", script.display(), e),
+        Ok(Ok(status)) if !status.success() => println!(
+            "Lease script {} for {} event failed: {}",
+            script.display(),
+            event,
+            status
+        ),
+        Ok(Ok(_)) => (),
+    }
+}
+
+/// Runs the configured lease script (if any) in the background.
+pub fn run_script(conf: &config::Config, event: LeaseEvent, details: LeaseDetails) {
+    if let Some(script) = &conf.lease_script {
+        tokio::spawn(run(
+            script.clone(),
+            conf.lease_script_timeout.unwrap_or(DEFAULT_SCRIPT_TIMEOUT),
+            event,
+            details,
+        ));
+    }
+}
+
+#[test]
+fn test_environment() {
+    let details = LeaseDetails {
+        address: Some("192.0.2.10".parse().unwrap()),
+        chaddr: Some(vec![0x00, 0x00, 0x5E, 0x00, 0x53, 0x01]),
+        clientid: Some(vec![0x01, 0x02]),
+        hostname: None,
+        interface: Some("eth0".into()),
+    };
+    assert_eq!(
+        details.environment(),
+        vec![
+            ("ERBIUM_ADDRESS", "192.0.2.10".to_string()),
+            ("ERBIUM_MAC", "00:00:5e:00:53:01".to_string()),
+            ("ERBIUM_CLIENT_ID", "0102".to_string()),
+            ("ERBIUM_INTERFACE", "eth0".to_string()),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_run_script() {
+    use std::os::unix::fs::PermissionsExt;
+    let dir = crate::test_util::TestDir::new("hook");
+    let script = dir.join("hook.sh");
+    let output = dir.join("output");
+    std::fs::write(
+        &script,
+        format!(
+            "#!/bin/sh\necho \"$1 $ERBIUM_ADDRESS $ERBIUM_HOSTNAME\" > {}\n",
+            output.display()
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let details = LeaseDetails {
+        address: Some("192.0.2.10".parse().unwrap()),
+        hostname: Some("myhost".into()),
+        ..Default::default()
+    };
+    run(
+        script,
+        DEFAULT_SCRIPT_TIMEOUT,
+        LeaseEvent::Released,
+        details,
+    )
+    .await;
+    assert_eq!(
+        std::fs::read_to_string(&output).unwrap(),
+        "released 192.0.2.10 myhost\n"
+    );
+}
@@ -36,7 +36,10 @@ pub mod dhcppkt;
 #[cfg(not(fuzzing))]
 mod dhcppkt;
 
+mod forcerenew;
+mod hooks;
 pub mod pool;
+mod probe;
 
 #[cfg(test)]
 mod test;
@@ -83,6 +86,8 @@ pub struct DHCPRequest {
     pub serverip: std::net::Ipv4Addr,
     /// The interface index that the request was received on.
     pub ifindex: u32,
+    /// The name of the interface that the request was received on, if known.
+    pub ifname: Option<String>,
 }
 
 #[cfg(test)]
@@ -111,10 +116,45 @@ impl std::default::Default for DHCPRequest {
             },
             serverip: "0.0.0.0".parse().unwrap(),
             ifindex: 0,
+            ifname: None,
         }
     }
 }
 
+/* Which network does this client live on?
+ *
+ * RFC3011 Section 3: If the server receives a DHCP packet with the subnet selection option, it
+ * MUST use the subnet selection option to select the subnet on which to allocate an address,
+ * instead of the 'giaddr' or the interface the packet arrived on.
+ *
+ * RFC3527 Section 4: A relay agent can likewise specify the link by including a link-selection
+ * sub-option in the relay agent information option.  If both are present the subnet selection
+ * option takes precedence.
+ *
+ * Otherwise it's the relay that forwarded the packet to us (giaddr), or if it wasn't relayed,
+ * the address of the interface we received it on.
+ */
+fn get_link_address(req: &DHCPRequest) -> net::Ipv4Addr {
+    if let Some(addr) = req
+        .pkt
+        .options
+        .get_option::<net::Ipv4Addr>(&dhcppkt::OPTION_SUBNETSELECT)
+    {
+        addr
+    } else if let Some(addr) = req
+        .pkt
+        .options
+        .get_relay_info()
+        .and_then(|info| info.link_selection)
+    {
+        addr
+    } else if !req.pkt.giaddr.is_unspecified() {
+        req.pkt.giaddr
+    } else {
+        req.serverip
+    }
+}
+
 #[derive(Eq, PartialEq, Debug)]
 enum PolicyMatch {
     NoMatch,
@@ -122,9 +162,50 @@ enum PolicyMatch {
     MatchSucceeded,
 }
 
+/* Matches a shell style glob pattern, where '*' matches any run of characters, and '?' matches
+ * exactly one character.  eg "vlan*" matches "vlan10".
+ */
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    /* Where to resume from if we need to backtrack to the last '*' */
+    let mut backtrack: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => {
+                if let Some((bp, bn)) = backtrack {
+                    /* Let the '*' swallow one more character, and try again */
+                    p = bp + 1;
+                    n = bn + 1;
+                    backtrack = Some((bp, bn + 1));
+                } else {
+                    return false;
+                }
+            }
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
 fn check_policy(req: &DHCPRequest, policy: &config::Policy) -> PolicyMatch {
     let mut outcome = PolicyMatch::NoMatch;
-    //if let Some(policy.match_interface ...
+    let relay_info = req.pkt.options.get_relay_info();
+    if let Some(match_interface) = &policy.match_interface {
+        outcome = PolicyMatch::MatchSucceeded;
+        match &req.ifname {
+            Some(ifname) if glob_match(match_interface, ifname) => (),
+            _ => return PolicyMatch::MatchFailed,
+        }
+    }
     if let Some(match_chaddr) = &policy.match_chaddr {
         outcome = PolicyMatch::MatchSucceeded;
         if req.pkt.chaddr != *match_chaddr {
@@ -133,7 +214,23 @@ fn check_policy(req: &DHCPRequest, policy: &config::Policy) -> PolicyMatch {
     }
     if let Some(match_subnet) = &policy.match_subnet {
         outcome = PolicyMatch::MatchSucceeded;
-        if !match_subnet.contains(req.serverip) {
+        if !match_subnet.contains(get_link_address(req)) {
+            return PolicyMatch::MatchFailed;
+        }
+    }
+    if let Some(match_circuit_id) = &policy.match_circuit_id {
+        outcome = PolicyMatch::MatchSucceeded;
+        if relay_info
+            .as_ref()
+            .and_then(|info| info.circuit_id.as_ref())
+            != Some(match_circuit_id)
+        {
+            return PolicyMatch::MatchFailed;
+        }
+    }
+    if let Some(match_remote_id) = &policy.match_remote_id {
+        outcome = PolicyMatch::MatchSucceeded;
+        if relay_info.as_ref().and_then(|info| info.remote_id.as_ref()) != Some(match_remote_id) {
             return PolicyMatch::MatchFailed;
         }
     }
@@ -166,6 +263,26 @@ fn apply_policy(req: &DHCPRequest, policy: &config::Policy, response: &mut Respo
         response.address = Some(address.clone()); /* HELP: I tried to make the lifetimes worked, and failed */
     }
 
+    if let Some(subnet) = &policy.match_subnet {
+        response.subnet = Some(subnet.clone());
+    }
+
+    if let Some(default_lease) = policy.apply_default_lease {
+        response.minlease = Some(default_lease);
+    }
+
+    if let Some(max_lease) = policy.apply_max_lease {
+        response.maxlease = Some(max_lease);
+    }
+
+    if let Some(probe) = policy.apply_probe {
+        response.probe = Some(probe);
+    }
+
+    if let Some(probe_timeout) = policy.apply_probe_timeout {
+        response.probe_timeout = Some(probe_timeout);
+    }
+
     // TODO: This should probably just be a u128 bitvector
     let pl: std::collections::HashSet<
         dhcppkt::DhcpOption,
@@ -181,7 +298,7 @@ fn apply_policy(req: &DHCPRequest, policy: &config::Policy, response: &mut Respo
     );
 
     for (k, v) in &policy.apply_other {
-        if pl.contains(k) {
+        if pl.contains(k) || is_lease_time_option(k) {
             response.options.mutate_option(k, v);
         }
     }
@@ -209,8 +326,10 @@ fn check_policies(req: &DHCPRequest, policies: &[config::Policy]) -> bool {
 }
 
 fn apply_policies(req: &DHCPRequest, policies: &[config::Policy], response: &mut Response) -> bool {
-    for policy in policies {
+    for (i, policy) in policies.iter().enumerate() {
         if apply_policy(req, policy, response) {
+            /* Any subpolicies have already recorded themselves, so we go in front of them */
+            response.policy.insert(0, i);
             return true;
         }
     }
@@ -223,6 +342,144 @@ struct Response {
     address: Option<pool::PoolAddresses>,
     minlease: Option<std::time::Duration>,
     maxlease: Option<std::time::Duration>,
+    probe: Option<config::Probe>,
+    probe_timeout: Option<std::time::Duration>,
+    /// The most specific subnet that the client matched.
+    subnet: Option<crate::net::Ipv4Subnet>,
+    /// The index of the policy that matched at each level of nesting.
+    policy: Vec<usize>,
+}
+
+/* How (if at all) to check that an address is unused before offering it to this client. */
+fn get_probe(
+    req: &DHCPRequest,
+    conf: &super::config::Config,
+) -> (config::Probe, std::time::Duration) {
+    let mut response: Response = Default::default();
+    apply_policies(req, &conf.dhcp.policies, &mut response);
+    let probe = match response.probe.unwrap_or(config::Probe::None) {
+        /* We can't ARP for addresses on the far side of a relay */
+        config::Probe::Arp if !req.pkt.giaddr.is_unspecified() => config::Probe::Icmp,
+        probe => probe,
+    };
+    (
+        probe,
+        response
+            .probe_timeout
+            .unwrap_or(probe::DEFAULT_PROBE_TIMEOUT),
+    )
+}
+
+/* The details we record in the lease database about a client.  Policies are unnamed, so we
+ * identify them by their position, eg "1.0" is the first subpolicy of the second policy.
+ */
+fn get_client_info(req: &DHCPRequest, policy: &[usize]) -> pool::ClientInfo {
+    let get_string = |option| {
+        req.pkt
+            .options
+            .get_raw_option(option)
+            .map(|v| String::from_utf8_lossy(v).into_owned())
+    };
+    pool::ClientInfo {
+        chaddr: Some(req.pkt.chaddr.clone()),
+        hostname: get_string(&dhcppkt::OPTION_HOSTNAME),
+        vendor_class: get_string(&dhcppkt::OPTION_VENDOR_CLASS),
+        policy: Some(
+            policy
+                .iter()
+                .map(|i| i.to_string())
+                .collect::<Vec<String>>()
+                .join("."),
+        ),
+    }
+}
+
+/* Find the reservation for this client, if any.  A client might match more than one reservation,
+ * in which case the client identifier is the most specific, and the (client chosen) host name the
+ * least.
+ */
+fn find_reservation<'r>(
+    req: &DHCPRequest,
+    reservations: &'r [config::Reservation],
+) -> Option<&'r config::Reservation> {
+    let clientid = req.pkt.options.get_clientid();
+    let hostname = req.pkt.options.get_raw_option(&dhcppkt::OPTION_HOSTNAME);
+    reservations
+        .iter()
+        .filter_map(|r| match &r.key {
+            config::ReservationKey::ClientId(c) if Some(c) == clientid.as_ref() => Some((0, r)),
+            config::ReservationKey::HardwareAddress(h) if *h == req.pkt.chaddr => Some((1, r)),
+            config::ReservationKey::HostName(n) if Some(n.as_bytes()) == hostname => Some((2, r)),
+            _ => None,
+        })
+        .min_by_key(|(priority, _)| *priority)
+        .map(|(_, r)| r)
+}
+
+/* Reserved addresses are only ever given to the client they're reserved for, and a client with a
+ * reservation is only ever given its reserved address.  The exception is when the client turns up
+ * somewhere its reserved address can't be used (eg on another VLAN, or behind another relay), in
+ * which case it is given an address from the pool of the policy it matched instead.
+ */
+fn apply_reservations(
+    req: &DHCPRequest,
+    reservations: &[config::Reservation],
+    subnet: Option<&crate::net::Ipv4Subnet>,
+    addresses: pool::PoolAddresses,
+) -> pool::PoolAddresses {
+    let usable = |address: &net::Ipv4Addr| {
+        addresses.contains(address) || matches!(subnet, Some(subnet) if subnet.contains(*address))
+    };
+    match find_reservation(req, reservations) {
+        Some(reservation) if usable(&reservation.address) => {
+            std::iter::once(reservation.address).collect()
+        }
+        _ => {
+            let mut addresses = addresses;
+            for reservation in reservations {
+                addresses.remove(&reservation.address);
+            }
+            addresses
+        }
+    }
+}
+
+/* RFC2131 Section 4.3.1: The server MUST return to the client:
+ *   o The client's network address, determined by the rules given earlier in this section,
+ *   o The expiration time for the lease,
+ *   o Parameters requested by the client, according to the following rules: ...
+ *
+ * T1 and T2 are part of the lease along with its expiration time, so the ones a policy sets are
+ * sent whether or not the client asked for them.
+ */
+fn is_lease_time_option(option: &dhcppkt::DhcpOption) -> bool {
+    *option == dhcppkt::OPTION_RENEWALTIME || *option == dhcppkt::OPTION_REBINDTIME
+}
+
+/* RFC2131 Section 4.4.5: T1 defaults to (0.5 * duration_of_lease).  T2 defaults to (0.875 *
+ * duration_of_lease).
+ *
+ * If the policy has explicitly configured renewal/rebind times then those are used instead.
+ */
+fn set_lease_times(options: dhcppkt::DhcpOptions, lease: &pool::Lease) -> dhcppkt::DhcpOptions {
+    let duration = lease.expire.as_secs() as u32;
+    let mut options = options.set_option(&dhcppkt::OPTION_LEASETIME, &duration);
+    if options
+        .get_raw_option(&dhcppkt::OPTION_RENEWALTIME)
+        .is_none()
+    {
+        options = options.set_option(&dhcppkt::OPTION_RENEWALTIME, &(duration / 2));
+    }
+    if options
+        .get_raw_option(&dhcppkt::OPTION_REBINDTIME)
+        .is_none()
+    {
+        options = options.set_option(
+            &dhcppkt::OPTION_REBINDTIME,
+            &((u64::from(duration) * 7 / 8) as u32),
+        );
+    }
+    options
 }
 
 fn handle_discover<'l>(
@@ -242,8 +499,15 @@ fn handle_discover<'l>(
     if !apply_policies(req, &conf.dhcp.policies, &mut response) {
         Err(DhcpError::NoPolicyConfigured)
     } else if let Some(addresses) = response.address {
+        let addresses = apply_reservations(
+            req,
+            &conf.dhcp.reservations,
+            response.subnet.as_ref(),
+            addresses,
+        );
         match pools.allocate_address(
             &req.pkt.get_client_id(),
+            &get_client_info(req, &response.policy),
             req.pkt.options.get_address_request(),
             &addresses,
             response.minlease.unwrap_or(pool::DEFAULT_MIN_LEASE),
@@ -264,10 +528,13 @@ fn handle_discover<'l>(
                 chaddr: req.pkt.chaddr.clone(),
                 sname: vec![],
                 file: vec![],
-                options: response
-                    .options
-                    .clone()
-                    .set_option(&dhcppkt::OPTION_SERVERID, &req.serverip),
+                options: set_lease_times(
+                    response
+                        .options
+                        .clone()
+                        .set_option(&dhcppkt::OPTION_SERVERID, &req.serverip),
+                    &lease,
+                ),
             }),
             Err(pool::Error::NoAssignableAddress) => Err(DhcpError::NoLeasesAvailable),
             Err(e) => Err(DhcpError::InternalError(e.to_string())),
@@ -277,6 +544,68 @@ fn handle_discover<'l>(
     }
 }
 
+/* RFC2131 Section 4.3.2: The client can send a DHCPREQUEST in a number of different states, which
+ * are distinguished by which fields it fills in.
+ */
+#[derive(Debug, PartialEq, Eq)]
+enum RequestState {
+    /// Responding to a DHCPOFFER: 'server identifier' is set, 'ciaddr' is zero.
+    Selecting,
+    /// Verifying a previously allocated address after a reboot: no 'server identifier', and
+    /// 'ciaddr' is zero.
+    InitReboot,
+    /// Extending a lease: no 'server identifier', 'ciaddr' is the client's address.  (Renewing
+    /// is unicast and rebinding is broadcast, but we treat them the same).
+    RenewingOrRebinding,
+}
+
+fn get_request_state(req: &DHCPRequest) -> RequestState {
+    if req.pkt.options.get_serverid().is_some() {
+        RequestState::Selecting
+    } else if req.pkt.ciaddr.is_unspecified() {
+        RequestState::InitReboot
+    } else {
+        RequestState::RenewingOrRebinding
+    }
+}
+
+fn mk_nak(req: &DHCPRequest, message: &str) -> dhcppkt::DHCP {
+    /* RFC2131 Section 4.3.2: If 'giaddr' is set in the DHCPREQUEST message, the client is on a
+     * different subnet.  The server MUST set the broadcast bit in the DHCPNAK, so that the relay
+     * agent will broadcast the DHCPNAK to the client.
+     */
+    let flags = if req.pkt.giaddr.is_unspecified() {
+        req.pkt.flags
+    } else {
+        req.pkt.flags | dhcppkt::FLAG_BROADCAST
+    };
+    dhcppkt::DHCP {
+        op: dhcppkt::OP_BOOTREPLY,
+        htype: dhcppkt::HWTYPE_ETHERNET,
+        hlen: 6,
+        hops: 0,
+        xid: req.pkt.xid,
+        secs: 0,
+        flags,
+        ciaddr: net::Ipv4Addr::UNSPECIFIED,
+        yiaddr: net::Ipv4Addr::UNSPECIFIED,
+        siaddr: net::Ipv4Addr::UNSPECIFIED,
+        giaddr: req.pkt.giaddr,
+        chaddr: req.pkt.chaddr.clone(),
+        sname: vec![],
+        file: vec![],
+        options: dhcppkt::DhcpOptions {
+            other: collections::HashMap::new(),
+        }
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPNAK)
+        .set_option(
+            &dhcppkt::OPTION_SERVERID,
+            &req.pkt.options.get_serverid().unwrap_or(req.serverip),
+        )
+        .set_option(&dhcppkt::OPTION_MESSAGE, &message.to_string()),
+    }
+}
+
 fn handle_request(
     pools: &mut pool::Pool,
     req: &DHCPRequest,
@@ -288,6 +617,10 @@ fn handle_request(
             return Err(DhcpError::OtherServer);
         }
     }
+    let requested = match get_request_state(req) {
+        RequestState::Selecting | RequestState::InitReboot => req.pkt.options.get_address_request(),
+        RequestState::RenewingOrRebinding => Some(req.pkt.ciaddr),
+    };
     let mut response: Response = Response {
         options: dhcppkt::DhcpOptions {
             other: collections::HashMap::new(),
@@ -299,42 +632,85 @@ fn handle_request(
     if !apply_policies(req, &conf.dhcp.policies, &mut response) {
         Err(DhcpError::NoPolicyConfigured)
     } else if let Some(addresses) = response.address {
-        match pools.allocate_address(
-            &req.pkt.get_client_id(),
-            if !req.pkt.ciaddr.is_unspecified() {
-                Some(req.pkt.ciaddr)
-            } else {
-                req.pkt.options.get_address_request()
+        let addresses = apply_reservations(
+            req,
+            &conf.dhcp.reservations,
+            response.subnet.as_ref(),
+            addresses,
+        );
+        let lease = if let Some(addr) = requested {
+            /* The client is asking for a specific address, we can't give them a different one,
+             * so we either give them exactly what they asked for, or NAK.
+             */
+            pools.confirm_address(
+                &req.pkt.get_client_id(),
+                &get_client_info(req, &response.policy),
+                addr,
+                &addresses,
+                response.minlease.unwrap_or(pool::DEFAULT_MIN_LEASE),
+                response.maxlease.unwrap_or(pool::DEFAULT_MAX_LEASE),
+            )
+        } else {
+            pools.allocate_address(
+                &req.pkt.get_client_id(),
+                &get_client_info(req, &response.policy),
+                None,
+                &addresses,
+                response.minlease.unwrap_or(pool::DEFAULT_MIN_LEASE),
+                response.maxlease.unwrap_or(pool::DEFAULT_MAX_LEASE),
+            )
+        };
+        let serverid = req.pkt.options.get_serverid().unwrap_or(req.serverip);
+        match lease {
+            Ok(lease) => {
+                let mut options = set_lease_times(
+                    response
+                        .options
+                        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPACK)
+                        .set_option(&dhcppkt::OPTION_SERVERID, &serverid),
+                    &lease,
+                );
+                /* Give clients that support it a key, so we can tell them to renew later. */
+                if forcerenew::is_nonce_capable(&req.pkt) {
+                    let nonce = forcerenew::mk_nonce();
+                    pools
+                        .set_forcerenew_nonce(lease.ip, &req.pkt.get_client_id(), serverid, &nonce)
+                        .map_err(|e| DhcpError::InternalError(e.to_string()))?;
+                    options = options.set_raw_option(
+                        &dhcppkt::OPTION_AUTHENTICATION,
+                        &forcerenew::mk_nonce_auth(&nonce),
+                    );
+                }
+                Ok(dhcppkt::DHCP {
+                    op: dhcppkt::OP_BOOTREPLY,
+                    htype: dhcppkt::HWTYPE_ETHERNET,
+                    hlen: 6,
+                    hops: 0,
+                    xid: req.pkt.xid,
+                    secs: 0,
+                    flags: req.pkt.flags,
+                    ciaddr: req.pkt.ciaddr,
+                    yiaddr: lease.ip,
+                    siaddr: net::Ipv4Addr::UNSPECIFIED,
+                    giaddr: req.pkt.giaddr,
+                    chaddr: req.pkt.chaddr.clone(),
+                    sname: vec![],
+                    file: vec![],
+                    options,
+                })
+            }
+            /* RFC2131 Section 4.3.2: If the DHCP server detects that the client's notion of its
+             * address is incorrect (e.g., the client has moved to a new subnet) then the server
+             * SHOULD send a DHCPNAK message to the client.
+             */
+            Err(pool::Error::NoAssignableAddress) => match requested {
+                Some(addr) if !addresses.contains(&addr) => Ok(mk_nak(
+                    req,
+                    "Requested address is not valid on this network",
+                )),
+                Some(_) => Ok(mk_nak(req, "Requested address is not available")),
+                None => Err(DhcpError::NoLeasesAvailable),
             },
-            &addresses,
-            response.minlease.unwrap_or(pool::DEFAULT_MIN_LEASE),
-            response.maxlease.unwrap_or(pool::DEFAULT_MAX_LEASE),
-        ) {
-            Ok(lease) => Ok(dhcppkt::DHCP {
-                op: dhcppkt::OP_BOOTREPLY,
-                htype: dhcppkt::HWTYPE_ETHERNET,
-                hlen: 6,
-                hops: 0,
-                xid: req.pkt.xid,
-                secs: 0,
-                flags: req.pkt.flags,
-                ciaddr: req.pkt.ciaddr,
-                yiaddr: lease.ip,
-                siaddr: net::Ipv4Addr::UNSPECIFIED,
-                giaddr: req.pkt.giaddr,
-                chaddr: req.pkt.chaddr.clone(),
-                sname: vec![],
-                file: vec![],
-                options: response
-                    .options
-                    .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPACK)
-                    .set_option(
-                        &dhcppkt::OPTION_SERVERID,
-                        &req.pkt.options.get_serverid().unwrap_or(req.serverip),
-                    )
-                    .set_option(&dhcppkt::OPTION_LEASETIME, &(lease.expire.as_secs() as u32)),
-            }),
-            Err(pool::Error::NoAssignableAddress) => Err(DhcpError::NoLeasesAvailable),
             Err(e) => Err(DhcpError::InternalError(e.to_string())),
         }
     } else {
@@ -342,6 +718,110 @@ fn handle_request(
     }
 }
 
+/* RFC2131 Section 4.3.5: The server responds to a DHCPINFORM message by sending a DHCPACK message
+ * directly to the address given in the 'ciaddr' field of the DHCPINFORM message.  The server MUST
+ * NOT send a lease expiration time to the client and SHOULD NOT fill in 'yiaddr'.
+ */
+fn handle_inform(
+    req: &DHCPRequest,
+    _serverids: ServerIds,
+    conf: &super::config::Config,
+) -> Result<dhcppkt::DHCP, DhcpError> {
+    let mut response: Response = Response {
+        options: dhcppkt::DhcpOptions {
+            other: collections::HashMap::new(),
+        }
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPACK)
+        .set_option(&dhcppkt::OPTION_SERVERID, &req.serverip),
+        ..Default::default()
+    };
+    /* We only want the options from the policy, the client already has an address, so we
+     * deliberately never look at the pool here.
+     */
+    if !apply_policies(req, &conf.dhcp.policies, &mut response) {
+        return Err(DhcpError::NoPolicyConfigured);
+    }
+    Ok(dhcppkt::DHCP {
+        op: dhcppkt::OP_BOOTREPLY,
+        htype: dhcppkt::HWTYPE_ETHERNET,
+        hlen: 6,
+        hops: 0,
+        xid: req.pkt.xid,
+        secs: 0,
+        flags: req.pkt.flags,
+        ciaddr: req.pkt.ciaddr,
+        yiaddr: net::Ipv4Addr::UNSPECIFIED,
+        siaddr: net::Ipv4Addr::UNSPECIFIED,
+        giaddr: req.pkt.giaddr,
+        chaddr: req.pkt.chaddr.clone(),
+        sname: vec![],
+        file: vec![],
+        /* There's no lease, so none of the times that go with one either */
+        options: response
+            .options
+            .remove_option(&dhcppkt::OPTION_LEASETIME)
+            .remove_option(&dhcppkt::OPTION_RENEWALTIME)
+            .remove_option(&dhcppkt::OPTION_REBINDTIME),
+    })
+}
+
+/* RFC2131 Section 4.4.1 (Table 5): The client MUST include the 'server identifier' option in
+ * DHCPDECLINE and DHCPRELEASE messages.
+ */
+fn check_required_serverid(req: &DHCPRequest, serverids: &ServerIds) -> Result<(), DhcpError> {
+    match req.pkt.options.get_serverid() {
+        Some(si) if serverids.contains(&si) => Ok(()),
+        Some(_) => Err(DhcpError::OtherServer),
+        None => Err(DhcpError::ParseError(dhcppkt::ParseError::InvalidPacket)),
+    }
+}
+
+fn handle_decline(
+    pools: &mut pool::Pool,
+    req: &DHCPRequest,
+    serverids: ServerIds,
+    conf: &super::config::Config,
+) -> Result<(), DhcpError> {
+    check_required_serverid(req, &serverids)?;
+    /* The address being declined is in the 'requested IP address' option */
+    let addr = req
+        .pkt
+        .options
+        .get_address_request()
+        .ok_or(DhcpError::ParseError(dhcppkt::ParseError::InvalidPacket))?;
+    let quarantine = conf
+        .dhcp
+        .decline_quarantine
+        .unwrap_or(pool::DEFAULT_DECLINE_QUARANTINE);
+    /* RFC2131 Section 4.3.3: The server MUST mark the network address as not available and SHOULD
+     * notify the local system administrator of a possible configuration problem.
+     */
+    println!(
+        "{}: Declined {}, possible address conflict.  Quarantining for {}s",
+        format_client(&req.pkt),
+        addr,
+        quarantine.as_secs()
+    );
+    pools
+        .decline_address(addr, &req.pkt.get_client_id(), quarantine)
+        .map_err(|e| DhcpError::InternalError(e.to_string()))
+}
+
+fn handle_release(
+    pools: &mut pool::Pool,
+    req: &DHCPRequest,
+    serverids: ServerIds,
+) -> Result<bool, DhcpError> {
+    check_required_serverid(req, &serverids)?;
+    /* The address being released is in 'ciaddr' */
+    if req.pkt.ciaddr.is_unspecified() {
+        return Err(DhcpError::ParseError(dhcppkt::ParseError::InvalidPacket));
+    }
+    pools
+        .release_address(req.pkt.ciaddr, &req.pkt.get_client_id())
+        .map_err(|e| DhcpError::InternalError(e.to_string()))
+}
+
 fn format_mac(v: &[u8]) -> String {
     v.iter()
         .map(|b| format!("{:0>2x}", b))
@@ -431,21 +911,102 @@ async fn log_pkt(request: &DHCPRequest, netinfo: &crate::net::netinfo::SharedNet
     );
 }
 
+/// The result of handling a DHCP packet.
+#[derive(Debug, Default, PartialEq)]
+pub struct Handled {
+    /// The reply to send, if any.
+    pub reply: Option<dhcppkt::DHCP>,
+    /// The address whose lease was released, so the lease script can be run for it.
+    pub released: Option<std::net::Ipv4Addr>,
+}
+
+impl Handled {
+    fn reply(reply: dhcppkt::DHCP) -> Self {
+        Handled {
+            reply: Some(reply),
+            released: None,
+        }
+    }
+}
+
+/// Handle a single DHCP packet, returning the reply to send (if any).  This doesn't run the lease
+/// script, that's left to the caller.
 pub fn handle_pkt(
     mut pools: &mut pool::Pool,
     request: &DHCPRequest,
     serverids: ServerIds,
     conf: &super::config::Config,
-) -> Result<dhcppkt::DHCP, DhcpError> {
-    match request.pkt.options.get_messagetype() {
-        Some(dhcppkt::DHCPDISCOVER) => handle_discover(&mut pools, &request, serverids, conf),
-        Some(dhcppkt::DHCPREQUEST) => handle_request(&mut pools, &request, serverids, conf),
+) -> Result<Handled, DhcpError> {
+    let handled = match request.pkt.options.get_messagetype() {
+        Some(dhcppkt::DHCPDISCOVER) => {
+            handle_discover(&mut pools, &request, serverids, conf).map(Handled::reply)
+        }
+        Some(dhcppkt::DHCPREQUEST) => {
+            handle_request(&mut pools, &request, serverids, conf).map(Handled::reply)
+        }
+        Some(dhcppkt::DHCPINFORM) => handle_inform(request, serverids, conf).map(Handled::reply),
+        Some(dhcppkt::DHCPDECLINE) => {
+            handle_decline(pools, request, serverids, conf).map(|_| Default::default())
+        }
+        Some(dhcppkt::DHCPRELEASE) => {
+            handle_release(pools, request, serverids).map(|released| Handled {
+                reply: None,
+                released: if released {
+                    Some(request.pkt.ciaddr)
+                } else {
+                    None
+                },
+            })
+        }
         Some(x) => Err(DhcpError::UnknownMessageType(x)),
         None => Err(DhcpError::ParseError(dhcppkt::ParseError::InvalidPacket)),
+    };
+    /* RFC3046 Section 2.2: DHCP servers claiming to support the Relay Agent Information option
+     * SHALL echo the entire contents of the Relay Agent Information option in all replies.
+     */
+    if let Some(relayinfo) = request
+        .pkt
+        .options
+        .get_raw_option(&dhcppkt::OPTION_RELAYINFO)
+    {
+        handled.map(|h| Handled {
+            reply: h.reply.map(|mut r| {
+                r.options = r
+                    .options
+                    .set_raw_option(&dhcppkt::OPTION_RELAYINFO, relayinfo);
+                r
+            }),
+            ..h
+        })
+    } else {
+        handled
     }
 }
 
-async fn send_raw(raw: Arc<raw::RawSocket>, buf: &[u8], intf: i32) -> Result<(), std::io::Error> {
+/* The sockets replies are sent on: directly attached clients may not have an address yet, so are
+ * sent raw ethernet frames, but relay agents are sent normal UDP packets.
+ */
+struct Sockets {
+    raw: raw::RawSocket,
+    udp: UdpSocket,
+}
+
+async fn send_relay(
+    udp: &UdpSocket,
+    buf: &[u8],
+    serverip: net::Ipv4Addr,
+    relay: net::Ipv4Addr,
+) -> Result<(), std::io::Error> {
+    udp.send_msg(
+        buf,
+        &udp::ControlMessage::new().set_send_from(Some(serverip.into())),
+        udp::MsgFlags::empty(),
+        Some(&net::SocketAddr::V4(net::SocketAddrV4::new(relay, 67))),
+    )
+    .await
+}
+
+async fn send_raw(raw: &raw::RawSocket, buf: &[u8], intf: i32) -> Result<(), std::io::Error> {
     raw.send_msg(
         buf,
         &mut raw::ControlMessage::new(),
@@ -467,6 +1028,49 @@ async fn send_raw(raw: Arc<raw::RawSocket>, buf: &[u8], intf: i32) -> Result<(),
     .map(|_| ())
 }
 
+/* RFC3203 Section 4: The server sends a unicast DHCPFORCERENEW message to the client.
+ *
+ * RFC6704 Section 3.3.1: Only clients that were given a nonce can authenticate it, so we only
+ * send them to those clients.
+ */
+fn get_forcerenew_leases(pools: &pool::Pool, policy: Option<&str>) -> Vec<pool::ForceRenewInfo> {
+    match pools.get_forcerenew_leases(policy) {
+        Ok(leases) => leases,
+        Err(e) => {
+            println!("Failed to find leases to send DHCPFORCERENEW to: {}", e);
+            vec![]
+        }
+    }
+}
+
+async fn send_forcerenew(udp: &UdpSocket, leases: Vec<pool::ForceRenewInfo>) {
+    for lease in leases {
+        println!(
+            "{}: Sending DHCPFORCERENEW for {}",
+            format_mac(&lease.chaddr),
+            lease.address
+        );
+        if let Err(e) = udp
+            .send_msg(
+                &forcerenew::mk_forcerenew(&lease),
+                &udp::ControlMessage::new().set_send_from(Some(lease.serverid.into())),
+                udp::MsgFlags::empty(),
+                Some(&net::SocketAddr::V4(net::SocketAddrV4::new(
+                    lease.address,
+                    68,
+                ))),
+            )
+            .await
+        {
+            println!(
+                "{}: Failed to send DHCPFORCERENEW: {}",
+                format_mac(&lease.chaddr),
+                e
+            );
+        }
+    }
+}
+
 async fn get_serverids(s: &SharedServerIds) -> ServerIds {
     s.lock().await.clone()
 }
@@ -475,8 +1079,20 @@ fn to_array(mac: &[u8]) -> Option<[u8; 6]> {
     mac[0..6].try_into().ok()
 }
 
+/* RFC2131 Section 4.1: In all cases, when 'giaddr' is zero, the server broadcasts any DHCPNAK
+ * messages to 0xffffffff.
+ */
+fn must_broadcast(reply: &dhcppkt::DHCP) -> bool {
+    reply.options.get_messagetype() == Some(dhcppkt::DHCPNAK) && reply.giaddr.is_unspecified()
+}
+
+/* How many addresses to try before giving up on a client, so a network full of squatters
+ * doesn't keep us probing forever.
+ */
+const MAX_PROBE_ATTEMPTS: usize = 3;
+
 async fn recvdhcp(
-    raw: Arc<raw::RawSocket>,
+    sockets: Arc<Sockets>,
     pools: Pool,
     serverids: SharedServerIds,
     pkt: &[u8],
@@ -515,39 +1131,128 @@ async fn recvdhcp(
         pkt: req,
         serverip: optional_dst.unwrap(),
         ifindex: intf,
+        ifname: netinfo.get_name_by_ifidx(intf).await,
     };
     log_pkt(&request, &netinfo).await;
 
     /* Now, lets process the packet we've found */
-    let reply;
-    {
-        /* Limit the amount of time we have these locked to just handling the packet */
-        let mut pool = pools.lock().await;
-        let lockedconf = conf.lock().await;
-
-        reply = match handle_pkt(
-            &mut pool,
-            &request,
-            get_serverids(&serverids).await,
-            &lockedconf,
-        ) {
+    let mut attempts = 0;
+    let reply = loop {
+        let reply;
+        let (probe, probe_timeout, quarantine);
+        {
+            /* Limit the amount of time we have these locked to just handling the packet */
+            let mut pool = pools.lock().await;
+            let lockedconf = conf.lock().await;
+
+            reply = match handle_pkt(
+                &mut pool,
+                &request,
+                get_serverids(&serverids).await,
+                &lockedconf,
+            ) {
+                Err(e) => {
+                    println!(
+                        "{}: Failed to handle {}: {}",
+                        format_client(&request.pkt),
+                        request
+                            .pkt
+                            .options
+                            .get_messagetype()
+                            .map(|x| x.to_string())
+                            .unwrap_or_else(|| "packet".into()),
+                        e
+                    );
+                    return;
+                }
+                Ok(Handled { reply: Some(r), .. }) => r,
+                /* There's no reply to a DHCPRELEASE, but the lease script is told about it */
+                Ok(Handled {
+                    released: Some(addr),
+                    ..
+                }) => {
+                    hooks::run_script(
+                        &lockedconf.dhcp,
+                        hooks::LeaseEvent::Released,
+                        hooks::LeaseDetails::from_request(&request, addr),
+                    );
+                    return;
+                }
+                /* Nothing to reply with (eg a DHCPDECLINE) */
+                Ok(_) => return,
+            };
+            let (p, t) = get_probe(&request, &lockedconf);
+            probe = p;
+            probe_timeout = t;
+            quarantine = lockedconf
+                .dhcp
+                .decline_quarantine
+                .unwrap_or(pool::DEFAULT_DECLINE_QUARANTINE);
+        }
+
+        /* Before offering an address, optionally check nobody is already (mis)using it.  The
+         * lease has already been recorded, so nobody else will be offered it while we wait.
+         */
+        if reply.options.get_messagetype() != Some(dhcppkt::DHCPOFFER)
+            || probe == config::Probe::None
+        {
+            break reply;
+        }
+        let (probe, ourmac) = match netinfo.get_linkaddr_by_ifidx(intf).await {
+            Some(crate::net::netinfo::LinkLayer::Ethernet(mac)) => (probe, mac),
+            _ => (config::Probe::Icmp, [0; 6]),
+        };
+        match probe::address_in_use(
+            probe,
+            intf,
+            &ourmac,
+            &request.pkt.chaddr,
+            reply.yiaddr,
+            probe_timeout,
+        )
+        .await
+        {
+            Ok(false) => break reply,
             Err(e) => {
                 println!(
-                    "{}: Failed to handle {}: {}",
-                    format_client(&request.pkt),
-                    request
-                        .pkt
-                        .options
-                        .get_messagetype()
-                        .map(|x| x.to_string())
-                        .unwrap_or_else(|| "packet".into()),
+                    "{}: Failed to probe {}, offering anyway: {}",
+                    format_client(&reply),
+                    reply.yiaddr,
                     e
                 );
-                return;
+                break reply;
             }
-            Ok(r) => r,
-        };
-    }
+            Ok(true) => (),
+        }
+        println!(
+            "{}: {} is already in use by another device.  Quarantining for {}s",
+            format_client(&reply),
+            reply.yiaddr,
+            quarantine.as_secs()
+        );
+        if let Err(e) = pools.lock().await.decline_address(
+            reply.yiaddr,
+            &request.pkt.get_client_id(),
+            quarantine,
+        ) {
+            println!(
+                "{}: Failed to quarantine {}: {}",
+                format_client(&reply),
+                reply.yiaddr,
+                e
+            );
+            return;
+        }
+        attempts += 1;
+        if attempts >= MAX_PROBE_ATTEMPTS {
+            println!(
+                "{}: Giving up after finding {} addresses in use",
+                format_client(&reply),
+                attempts
+            );
+            return;
+        }
+    };
 
     /* Now, we should have a packet ready to send */
     /* First, if we're claiming to be particular IP, we should remember that as an IP that is one
@@ -578,6 +1283,42 @@ async fn recvdhcp(
     );
     log_options(&reply);
 
+    if reply.options.get_messagetype() == Some(dhcppkt::DHCPACK) && !reply.yiaddr.is_unspecified() {
+        let event = if get_request_state(&request) == RequestState::RenewingOrRebinding {
+            hooks::LeaseEvent::Renewed
+        } else {
+            hooks::LeaseEvent::Granted
+        };
+        hooks::run_script(
+            &conf.lock().await.dhcp,
+            event,
+            hooks::LeaseDetails::from_request(&request, reply.yiaddr),
+        );
+    }
+
+    /* RFC2131 Section 4.1: If the 'giaddr' field in a DHCP message from a client is non-zero, the
+     * server sends any return messages to the 'DHCP server' port on the BOOTP relay agent whose
+     * address appears in 'giaddr'.
+     */
+    if !reply.giaddr.is_unspecified() {
+        if let Err(e) = send_relay(
+            &sockets.udp,
+            &reply.serialise(),
+            request.serverip,
+            reply.giaddr,
+        )
+        .await
+        {
+            println!(
+                "{}: Failed to send reply via relay {}: {:?}",
+                format_client(&reply),
+                reply.giaddr,
+                e
+            );
+        }
+        return;
+    }
+
     /* Collect metadata ready to send */
     let srcll = if let Some(crate::net::netinfo::LinkLayer::Ethernet(srcll)) =
         netinfo.get_linkaddr_by_ifidx(intf).await
@@ -588,8 +1329,13 @@ async fn recvdhcp(
         return;
     };
 
-    let chaddr = if let Some(chaddr) = to_array(&reply.chaddr) {
-        chaddr
+    let (dstip, chaddr) = if must_broadcast(&reply) {
+        (
+            net::SocketAddrV4::new(net::Ipv4Addr::BROADCAST, 68),
+            [0xff; 6],
+        )
+    } else if let Some(chaddr) = to_array(&reply.chaddr) {
+        (ip4, chaddr)
     } else {
         println!(
             "{}: Cannot send reply to invalid address {:?}",
@@ -604,17 +1350,68 @@ async fn recvdhcp(
     let etherbuf = packet::Fragment::new_udp(
         std::net::SocketAddrV4::new(request.serverip, 67),
         &srcll,
-        ip4,
+        dstip,
         &chaddr,
         packet::Tail::Payload(&replybuf),
     )
     .flatten();
 
-    if let Err(e) = send_raw(raw, &etherbuf, intf.try_into().unwrap()).await {
+    if let Err(e) = send_raw(&sockets.raw, &etherbuf, intf.try_into().unwrap()).await {
         println!("{}: Failed to send reply: {:?}", format_client(&reply), e);
     }
 }
 
+/* How often to look for leases that have expired, and how often to compact the database */
+const SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+const VACUUM_INTERVAL: std::time::Duration = std::time::Duration::from_secs(86400);
+
+async fn sweep_leases(pools: Pool, conf: super::config::SharedConfig) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    let mut last_vacuum = std::time::Instant::now();
+    loop {
+        interval.tick().await;
+        /* The locks are only held while they're needed, so packets and reloads aren't held up */
+        let history = conf
+            .lock()
+            .await
+            .dhcp
+            .lease_history
+            .unwrap_or(pool::DEFAULT_LEASE_HISTORY);
+        let expired = pools.lock().await.expire_leases(history);
+        match expired {
+            Ok(expired) => {
+                let lockedconf = conf.lock().await;
+                for lease in expired {
+                    hooks::run_script(
+                        &lockedconf.dhcp,
+                        hooks::LeaseEvent::Expired,
+                        hooks::LeaseDetails::from_lease(&lease),
+                    );
+                    println!(
+                        "{}: Lease for {} expired",
+                        lease
+                            .client
+                            .chaddr
+                            .as_ref()
+                            .or(lease.clientid.as_ref())
+                            .map(|v| format_mac(v))
+                            .unwrap_or_else(|| "-".into()),
+                        lease.address
+                    );
+                }
+            }
+            Err(e) => println!("Failed to expire leases: {}", e),
+        }
+        /* Compacting needs the database to itself, so DHCP waits for this, but not reloads */
+        if last_vacuum.elapsed() >= VACUUM_INTERVAL {
+            if let Err(e) = pools.lock().await.vacuum() {
+                println!("Failed to compact lease database: {}", e);
+            }
+            last_vacuum = std::time::Instant::now();
+        }
+    }
+}
+
 enum RunError {
     Io(std::io::Error),
     PoolError(pool::Error),
@@ -629,14 +1426,64 @@ impl ToString for RunError {
     }
 }
 
+/* When the configuration is reloaded, tell clients on policies that changed to renew so they pick
+ * up their new options.
+ */
+async fn forcerenew_changed_policies(
+    sockets: Arc<Sockets>,
+    pools: Pool,
+    conf: super::config::SharedConfig,
+    mut reloads: super::config::ConfigReloads,
+) {
+    let mut policies = conf.lock().await.dhcp.policies.clone();
+    while reloads.recv().await.is_some() {
+        let changes = {
+            let lockedconf = conf.lock().await;
+            if lockedconf.dhcp.policies == policies {
+                continue;
+            }
+            let changes = forcerenew::changed_policies(&policies, &lockedconf.dhcp.policies);
+            policies = lockedconf.dhcp.policies.clone();
+            changes
+        };
+        /* Find the clients to send to before updating the policies recorded with their leases,
+         * and update them before sending, so clients that renew straight away don't race with
+         * the update.
+         */
+        let mut leases = vec![];
+        {
+            let mut pool = pools.lock().await;
+            for policy in &changes.changed {
+                println!(
+                    "Policy {} changed, sending DHCPFORCERENEW to its clients",
+                    policy
+                );
+                leases.extend(get_forcerenew_leases(&pool, Some(policy)));
+            }
+            if let Err(e) = pool.update_policies(&changes.moved, &changes.changed) {
+                println!("Failed to update the policies of leases: {}", e);
+            }
+        }
+        send_forcerenew(&sockets.udp, leases).await;
+    }
+}
+
 async fn run_internal(
     netinfo: crate::net::netinfo::SharedNetInfo,
     conf: super::config::SharedConfig,
+    reloads: super::config::ConfigReloads,
 ) -> Result<(), RunError> {
     println!("Starting DHCP service");
-    let rawsock = Arc::new(raw::RawSocket::new().map_err(RunError::Io)?);
+    let lease_database = conf
+        .lock()
+        .await
+        .dhcp
+        .lease_database
+        .clone()
+        .unwrap_or_else(|| pool::DEFAULT_LEASE_DATABASE.into());
+    println!("Using lease database {}", lease_database.display());
     let pools = Arc::new(sync::Mutex::new(
-        pool::Pool::new().map_err(RunError::PoolError)?,
+        pool::Pool::open(&lease_database).map_err(RunError::PoolError)?,
     ));
     let serverids: SharedServerIds = Arc::new(sync::Mutex::new(std::collections::HashSet::new()));
     let listener = UdpSocket::bind("0.0.0.0:67").await.map_err(RunError::Io)?;
@@ -647,14 +1494,40 @@ async fn run_internal(
         "Listening for DHCP on {}",
         listener.local_addr().map_err(RunError::Io)?
     );
+    let sockets = Arc::new(Sockets {
+        raw: raw::RawSocket::new().map_err(RunError::Io)?,
+        udp: listener,
+    });
+
+    tokio::spawn(sweep_leases(pools.clone(), conf.clone()));
+    tokio::spawn(forcerenew_changed_policies(
+        sockets.clone(),
+        pools.clone(),
+        conf.clone(),
+        reloads,
+    ));
+
+    /* SIGUSR1 tells every client that supports it to renew, eg to pick up changed options */
+    let mut usr1 = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1())
+        .map_err(RunError::Io)?;
+    let rs = sockets.clone();
+    let p = pools.clone();
+    tokio::spawn(async move {
+        while usr1.recv().await.is_some() {
+            println!("Received SIGUSR1, sending DHCPFORCERENEW to all clients");
+            let leases = get_forcerenew_leases(&*p.lock().await, None);
+            send_forcerenew(&rs.udp, leases).await;
+        }
+    });
 
     loop {
-        let rm = listener
+        let rm = sockets
+            .udp
             .recv_msg(65536, udp::MsgFlags::empty())
             .await
             .map_err(RunError::Io)?;
         let p = pools.clone();
-        let rs = rawsock.clone();
+        let rs = sockets.clone();
         let s = serverids.clone();
         let ni = netinfo.clone();
         let c = conf.clone();
@@ -677,13 +1550,29 @@ async fn run_internal(
 pub async fn run(
     netinfo: crate::net::netinfo::SharedNetInfo,
     conf: super::config::SharedConfig,
+    reloads: super::config::ConfigReloads,
 ) -> Result<(), String> {
-    match run_internal(netinfo, conf).await {
+    match run_internal(netinfo, conf, reloads).await {
         Ok(_) => Ok(()),
         Err(e) => Err(e.to_string()),
     }
 }
 
+#[test]
+fn test_glob_match() {
+    assert!(glob_match("eth0", "eth0"));
+    assert!(!glob_match("eth0", "eth01"));
+    assert!(!glob_match("eth01", "eth0"));
+    assert!(glob_match("vlan*", "vlan10"));
+    assert!(glob_match("vlan*", "vlan"));
+    assert!(!glob_match("vlan*", "eth0"));
+    assert!(glob_match("*0", "vlan10"));
+    assert!(glob_match("v*n*0", "vlan10"));
+    assert!(glob_match("eth?", "eth1"));
+    assert!(!glob_match("eth?", "eth"));
+    assert!(glob_match("*", ""));
+}
+
 #[test]
 fn test_policy() {
     let cfg = config::Policy {
@@ -705,6 +1594,8 @@ async fn test_config_parse() -> Result<(), Box<dyn std::error::Error>> {
     let cfg = crate::config::load_config_from_string_for_test(
         "---
 dhcp:
+    decline-quarantine: 30m
+    lease-database: ':memory:'
     policies:
       - match-subnet: 192.168.0.0/24
         apply-dns-servers: ['8.8.8.8', '8.8.4.4']
@@ -722,6 +1613,7 @@ dhcp:
         policies:
            - { match-host-name: myhost, apply-address: 192.168.0.1 }
            - { match-hardware-address: 00:01:02:03:04:05, apply-address: 192.168.0.2 }
+           - { match-circuit-id: eth0/1, match-remote-id: switch1, apply-address: 192.168.0.3 }
 
 
       - match-interface: dmz
@@ -768,6 +1660,7 @@ dhcp:
             },
             serverip: "192.168.0.67".parse().unwrap(),
             ifindex: 1,
+            ifname: Some("eth0".into()),
         },
         &cfg.lock().await.dhcp.policies,
         &mut resp,
@@ -777,6 +1670,15 @@ dhcp:
 
     println!("{:?}", cfg.lock().await);
 
+    assert_eq!(
+        cfg.lock().await.dhcp.lease_database,
+        Some(":memory:".into())
+    );
+    assert_eq!(
+        cfg.lock().await.dhcp.decline_quarantine,
+        Some(std::time::Duration::from_secs(30 * 60))
+    );
+
     assert_eq!(
         resp.address,
         Some(std::collections::HashSet::from_iter(
@@ -23,6 +23,9 @@ use std::hash::Hasher;
 
 pub const DEFAULT_MIN_LEASE: std::time::Duration = std::time::Duration::from_secs(300);
 pub const DEFAULT_MAX_LEASE: std::time::Duration = std::time::Duration::from_secs(86400);
+pub const DEFAULT_DECLINE_QUARANTINE: std::time::Duration = std::time::Duration::from_secs(3600);
+pub const DEFAULT_LEASE_DATABASE: &str = "/var/lib/erbium/leases.sqlite";
+pub const DEFAULT_LEASE_HISTORY: usize = 4;
 
 pub type PoolAddresses = std::collections::HashSet<std::net::Ipv4Addr>;
 
@@ -32,6 +35,85 @@ pub struct Lease {
     pub expire: std::time::Duration,
 }
 
+/// Details about the client that are recorded with its lease.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ClientInfo {
+    pub chaddr: Option<Vec<u8>>,
+    /// The host name the client supplied (option 12).
+    pub hostname: Option<String>,
+    /// The vendor class the client supplied (option 60).
+    pub vendor_class: Option<String>,
+    /// Which policy the client matched.
+    pub policy: Option<String>,
+}
+
+/// A lease as recorded in the lease database.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeaseInfo {
+    pub address: std::net::Ipv4Addr,
+    pub clientid: Option<Vec<u8>>,
+    pub client: ClientInfo,
+    /// Seconds since the unix epoch that the lease was (last) granted.
+    pub start: u64,
+    /// Seconds since the unix epoch that the lease expires.
+    pub expiry: u64,
+}
+
+impl LeaseInfo {
+    pub fn is_active(&self, now: u64) -> bool {
+        self.expiry >= now
+    }
+}
+
+/// What's needed to send a DHCPFORCERENEW to a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForceRenewInfo {
+    pub address: std::net::Ipv4Addr,
+    pub chaddr: Vec<u8>,
+    /// The server identifier the client was given.
+    pub serverid: std::net::Ipv4Addr,
+    /// The key used to authenticate the DHCPFORCERENEW.
+    pub nonce: Vec<u8>,
+}
+
+/// Which leases to return from `Pool::get_leases`.
+#[derive(Debug, Clone, Default)]
+pub struct LeaseFilter {
+    /// Only return leases for addresses inside this subnet.
+    pub subnet: Option<crate::net::Ipv4Subnet>,
+    /// Only return leases for this hardware address.
+    pub chaddr: Option<Vec<u8>>,
+    /// Also return leases that have expired.
+    pub include_expired: bool,
+}
+
+impl LeaseFilter {
+    fn matches(&self, lease: &LeaseInfo, now: u64) -> bool {
+        if !self.include_expired && !lease.is_active(now) {
+            return false;
+        }
+        if let Some(subnet) = &self.subnet {
+            if !subnet.contains(lease.address) {
+                return false;
+            }
+        }
+        if let Some(chaddr) = &self.chaddr {
+            /* Clients that don't send a client identifier are identified by their chaddr, and
+             * most clients that do use their hardware type followed by their chaddr.
+             */
+            let mut ethernet_clientid = vec![1];
+            ethernet_clientid.extend_from_slice(chaddr);
+            if lease.client.chaddr.as_ref() != Some(chaddr)
+                && lease.clientid.as_ref() != Some(chaddr)
+                && lease.clientid != Some(ethernet_clientid)
+            {
+                return false;
+            }
+        }
+        true
+    }
+}
+
 pub struct Pool {
     conn: rusqlite::Connection,
 }
@@ -41,6 +123,8 @@ pub enum Error {
     DbError(String, rusqlite::Error),
     NoSuchPool(String),
     CorruptDatabase(String),
+    OutdatedDatabase(String),
+    InvalidDatabasePath(String),
     NoAssignableAddress,
 }
 
@@ -50,17 +134,146 @@ impl std::fmt::Display for Error {
             Error::DbError(reason, e) => write!(f, "{}: {}", reason, e),
             Error::NoSuchPool(s) => write!(f, "No Such Pool: {}", s),
             Error::CorruptDatabase(s) => write!(f, "Corrupt Database: {}", s),
+            Error::OutdatedDatabase(s) => write!(f, "Outdated Database: {}", s),
+            Error::InvalidDatabasePath(s) => write!(f, "Unusable lease database path: {}", s),
             Error::NoAssignableAddress => write!(f, "No Assignable Address"),
         }
     }
 }
 
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::DbError(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
 impl Error {
     fn emit(reason: String, e: rusqlite::Error) -> Error {
         Error::DbError(reason, e)
     }
 }
 
+/* The lease database schema, as an ordered list of migrations.  Migration i upgrades the schema
+ * from version i to version i+1, so a new database runs all of them in turn.  Never change a
+ * migration once it has been released, append a new one instead.
+ */
+const MIGRATIONS: &[&str] = &[
+    /* Version 1: The original leases table */
+    "CREATE TABLE leases (
+      address TEXT NOT NULL,
+      chaddr BLOB,
+      clientid BLOB,
+      start INTEGER NOT NULL,
+      expiry INTEGER NOT NULL,
+      PRIMARY KEY (address)
+    );",
+    /* Version 2: Record details about the client */
+    "ALTER TABLE leases ADD COLUMN hostname TEXT;
+     ALTER TABLE leases ADD COLUMN vendorclass TEXT;
+     ALTER TABLE leases ADD COLUMN policy TEXT;",
+    /* Version 3: Forcerenew nonces (RFC6704) */
+    "ALTER TABLE leases ADD COLUMN serverid TEXT;
+     ALTER TABLE leases ADD COLUMN forcerenew_nonce BLOB;",
+    /* Version 4: Keep expired leases separately */
+    "CREATE TABLE lease_history (
+      address TEXT NOT NULL,
+      clientid BLOB NOT NULL,
+      chaddr BLOB,
+      hostname TEXT,
+      vendorclass TEXT,
+      policy TEXT,
+      start INTEGER NOT NULL,
+      expiry INTEGER NOT NULL
+    );
+    CREATE INDEX lease_history_clientid ON lease_history (clientid);",
+];
+
+fn table_exists(conn: &rusqlite::Connection, table: &str) -> Result<bool, Error> {
+    conn.query_row(
+        "SELECT count(*) FROM sqlite_master WHERE type='table' AND name=?1",
+        rusqlite::params![table],
+        |row| row.get::<usize, u32>(0),
+    )
+    .map(|count| count > 0)
+    .map_err(|e| Error::emit(format!("Checking for table {}", table), e))
+}
+
+/* Works out the schema version.  This never changes the database, so is safe on a read only
+ * database.
+ */
+fn read_schema_version(conn: &rusqlite::Connection) -> Result<usize, Error> {
+    if table_exists(conn, "schema_version")? {
+        return conn
+            .query_row(
+                "SELECT version FROM schema_version",
+                rusqlite::params![],
+                |row| row.get::<usize, u32>(0),
+            )
+            .map(|v| v as usize)
+            .map_err(|e| Error::emit("Reading schema version".into(), e));
+    }
+
+    /* Databases from before schema versioning was added only ever had the original leases
+     * table.
+     */
+    Ok(if table_exists(conn, "leases")? { 1 } else { 0 })
+}
+
+/* Adds the schema_version table, which databases from before schema versioning don't have. */
+fn create_schema_version(conn: &rusqlite::Connection, version: usize) -> Result<(), Error> {
+    conn.execute_batch("CREATE TABLE schema_version (version INTEGER NOT NULL);")
+        .map_err(|e| Error::emit("Creating table schema_version".into(), e))?;
+    conn.execute(
+        "INSERT INTO schema_version (version) VALUES (?1)",
+        rusqlite::params![version as u32],
+    )
+    .map_err(|e| Error::emit("Setting schema version".into(), e))?;
+    Ok(())
+}
+
+fn check_not_newer(version: usize, latest: usize) -> Result<(), Error> {
+    if version > latest {
+        return Err(Error::CorruptDatabase(format!(
+            "Schema version {} is newer than the latest supported version {}",
+            version, latest
+        )));
+    }
+    Ok(())
+}
+
+/* Brings the database schema up to date.  This is done in a single transaction, so if any
+ * migration fails the database is left as it was.  A database that is already up to date isn't
+ * written to at all.
+ */
+fn migrate(conn: &mut rusqlite::Connection, migrations: &[&str]) -> Result<(), Error> {
+    let tx = conn
+        .transaction()
+        .map_err(|e| Error::emit("Starting schema upgrade".into(), e))?;
+    let version = read_schema_version(&tx)?;
+    check_not_newer(version, migrations.len())?;
+    if version == migrations.len() {
+        return Ok(());
+    }
+    if !table_exists(&tx, "schema_version")? {
+        create_schema_version(&tx, version)?;
+    }
+    for (i, migration) in migrations.iter().enumerate().skip(version) {
+        println!("Upgrading lease database to schema version {}", i + 1);
+        tx.execute_batch(migration)
+            .map_err(|e| Error::emit(format!("Upgrading to schema version {}", i + 1), e))?;
+    }
+    tx.execute(
+        "UPDATE schema_version SET version = ?1",
+        rusqlite::params![migrations.len() as u32],
+    )
+    .map_err(|e| Error::emit("Updating schema version".into(), e))?;
+    tx.commit()
+        .map_err(|e| Error::emit("Committing schema upgrade".into(), e))
+}
+
 fn calculate_hash<S: Hash, T: Hash>(s: &S, t: &T) -> u64 {
     let mut h = DefaultHasher::new();
     s.hash(&mut h);
@@ -69,21 +282,8 @@ fn calculate_hash<S: Hash, T: Hash>(s: &S, t: &T) -> u64 {
 }
 
 impl Pool {
-    fn setup_db(self) -> Result<Self, Error> {
-        self.conn
-            .execute(
-                "CREATE TABLE IF NOT EXISTS leases (
-              address TEXT NOT NULL,
-              chaddr BLOB,
-              clientid BLOB,
-              start INTEGER NOT NULL,
-              expiry INTEGER NOT NULL,
-              PRIMARY KEY (address)
-            )",
-                rusqlite::params![],
-            )
-            .map_err(|e| Error::emit("Creating table leases".into(), e))?;
-
+    fn setup_db(mut self) -> Result<Self, Error> {
+        migrate(&mut self.conn, MIGRATIONS)?;
         Ok(self)
     }
 
@@ -100,13 +300,310 @@ impl Pool {
     }
 
     pub fn new() -> Result<Pool, Error> {
-        let conn = rusqlite::Connection::open("/var/lib/erbium/leases.sqlite").map_err(|e| {
-            Error::emit("Creating database /var/lib/erbium/leases.sqlite".into(), e)
-        })?;
+        Self::open(std::path::Path::new(DEFAULT_LEASE_DATABASE))
+    }
+
+    /* sqlite's error for a database in a missing or unwritable directory is just "unable to open
+     * database file", so check for these ourselves to give a more useful error.
+     */
+    fn check_database_dir(path: &std::path::Path) -> Result<(), Error> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => std::path::Path::new("."),
+        };
+        match std::fs::metadata(dir) {
+            Err(e) => Err(Error::InvalidDatabasePath(format!(
+                "{}: Directory {}: {}",
+                path.display(),
+                dir.display(),
+                e
+            ))),
+            Ok(m) if !m.is_dir() => Err(Error::InvalidDatabasePath(format!(
+                "{}: {} is not a directory",
+                path.display(),
+                dir.display()
+            ))),
+            Ok(_) => nix::unistd::access(dir, nix::unistd::AccessFlags::W_OK).map_err(|e| {
+                Error::InvalidDatabasePath(format!(
+                    "{}: Directory {} is not writable: {}",
+                    path.display(),
+                    dir.display(),
+                    e
+                ))
+            }),
+        }
+    }
+
+    /// Opens (creating if necessary) the lease database at `path`.  The special path ":memory:"
+    /// uses a database in memory, which is lost when erbium exits.
+    pub fn open(path: &std::path::Path) -> Result<Pool, Error> {
+        if path == std::path::Path::new(":memory:") {
+            return Self::new_in_memory();
+        }
+        Self::check_database_dir(path)?;
+        let conn = rusqlite::Connection::open(path)
+            .map_err(|e| Error::emit(format!("Creating database {}", path.display()), e))?;
 
         Self::new_with_conn(conn)
     }
 
+    /// Opens an existing lease database at `path` for reading only, eg for listing leases.  This
+    /// doesn't need write access to the database or its directory.
+    pub fn open_read_only(path: &std::path::Path) -> Result<Pool, Error> {
+        let conn =
+            rusqlite::Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)
+                .map_err(|e| Error::emit(format!("Opening database {}", path.display()), e))?;
+        /* Upgrading the schema would need write access, so leave that to the server */
+        let version = read_schema_version(&conn)?;
+        check_not_newer(version, MIGRATIONS.len())?;
+        if version < MIGRATIONS.len() {
+            return Err(Error::OutdatedDatabase(format!(
+                "{} has schema version {}, but version {} is required",
+                path.display(),
+                version,
+                MIGRATIONS.len()
+            )));
+        }
+        Ok(Pool { conn })
+    }
+
+    /* Used to check we cope with being unable to write to the database (eg the disk is full) */
+    #[cfg(test)]
+    pub fn new_read_only_for_test() -> Result<Pool, Error> {
+        let pool = Self::new_in_memory()?;
+        pool.conn
+            .execute_batch("PRAGMA query_only = ON;")
+            .map_err(|e| Error::emit("Making database read only".into(), e))?;
+        Ok(pool)
+    }
+
+    /// Returns the leases in the database that match `filter`, ordered by address.
+    pub fn get_leases(&self, filter: &LeaseFilter) -> Result<Vec<LeaseInfo>, Error> {
+        let ts = std::time::SystemTime::now()
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .expect("clock failure")
+            .as_secs();
+        /* Leases that have been swept are kept in the history */
+        let sql = if filter.include_expired {
+            "SELECT address, chaddr, clientid, start, expiry, hostname, vendorclass, policy
+             FROM leases
+             UNION ALL
+             SELECT address, chaddr, clientid, start, expiry, hostname, vendorclass, policy
+             FROM lease_history"
+        } else {
+            "SELECT address, chaddr, clientid, start, expiry, hostname, vendorclass, policy
+             FROM leases"
+        };
+        let mut leases = query_leases(&self.conn, sql, rusqlite::params![])?;
+        leases.retain(|lease| filter.matches(lease, ts));
+        leases.sort_by_key(|lease| u32::from(lease.address));
+        Ok(leases)
+    }
+
+    /// Moves leases that have expired out of the lease table, keeping the most recent `history`
+    /// leases for each client so they can be given their previous address again later.  Returns
+    /// the client leases that expired.
+    pub fn expire_leases(&mut self, history: usize) -> Result<Vec<LeaseInfo>, Error> {
+        let ts = std::time::SystemTime::now()
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .expect("clock failure")
+            .as_secs();
+        let tx = self
+            .conn
+            .transaction()
+            .map_err(|e| Error::emit("Starting lease expiry".into(), e))?;
+        /* Leases without a client are quarantined addresses, which are just forgotten */
+        let expired = query_leases(
+            &tx,
+            "SELECT address, chaddr, clientid, start, expiry, hostname, vendorclass, policy
+             FROM leases
+             WHERE expiry < ?1
+             AND clientid IS NOT NULL",
+            rusqlite::params![ts as u32],
+        )?;
+        tx.execute(
+            "INSERT INTO lease_history
+               (address, clientid, chaddr, hostname, vendorclass, policy, start, expiry)
+             SELECT address, clientid, chaddr, hostname, vendorclass, policy, start, expiry
+             FROM leases
+             WHERE expiry < ?1
+             AND clientid IS NOT NULL",
+            rusqlite::params![ts as u32],
+        )
+        .map_err(|e| Error::emit("Recording lease history".into(), e))?;
+        tx.execute(
+            "DELETE FROM leases WHERE expiry < ?1",
+            rusqlite::params![ts as u32],
+        )
+        .map_err(|e| Error::emit("Removing expired leases".into(), e))?;
+        tx.execute(
+            "DELETE FROM lease_history
+             WHERE rowid IN (
+               SELECT old.rowid
+               FROM lease_history old
+               WHERE (SELECT count(*)
+                      FROM lease_history newer
+                      WHERE newer.clientid = old.clientid
+                      AND (newer.expiry > old.expiry
+                           OR (newer.expiry = old.expiry AND newer.rowid > old.rowid))) >= ?1
+             )",
+            rusqlite::params![history as u32],
+        )
+        .map_err(|e| Error::emit("Trimming lease history".into(), e))?;
+        tx.commit()
+            .map_err(|e| Error::emit("Committing lease expiry".into(), e))?;
+        Ok(expired)
+    }
+
+    /// Reclaims the space left behind by expired leases.
+    pub fn vacuum(&mut self) -> Result<(), Error> {
+        self.conn
+            .execute_batch("VACUUM;")
+            .map_err(|e| Error::emit("Compacting lease database".into(), e))
+    }
+
+    /// Records the forcerenew nonce (RFC6704) that was sent to the client with its lease.
+    pub fn set_forcerenew_nonce(
+        &mut self,
+        address: std::net::Ipv4Addr,
+        clientid: &[u8],
+        serverid: std::net::Ipv4Addr,
+        nonce: &[u8],
+    ) -> Result<(), Error> {
+        self.conn
+            .execute(
+                "UPDATE leases
+                 SET serverid = ?1,
+                     forcerenew_nonce = ?2
+                 WHERE address = ?3
+                 AND clientid = ?4",
+                rusqlite::params![serverid.to_string(), nonce, address.to_string(), clientid],
+            )
+            .map_err(|e| Error::emit("Recording forcerenew nonce".into(), e))?;
+        Ok(())
+    }
+
+    /// Returns the active leases that can be sent a DHCPFORCERENEW, optionally only those whose
+    /// clients matched `policy` (or one of its subpolicies).
+    pub fn get_forcerenew_leases(
+        &self,
+        policy: Option<&str>,
+    ) -> Result<Vec<ForceRenewInfo>, Error> {
+        let ts = std::time::SystemTime::now()
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .expect("clock failure")
+            .as_secs();
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT address, chaddr, serverid, forcerenew_nonce, policy
+                 FROM leases
+                 WHERE expiry >= ?1
+                 AND chaddr IS NOT NULL
+                 AND serverid IS NOT NULL
+                 AND forcerenew_nonce IS NOT NULL",
+            )
+            .map_err(|e| Error::emit("Querying leases".into(), e))?;
+        let rows = stmt
+            .query_map(rusqlite::params![ts as u32], |row| {
+                Ok((
+                    row.get::<usize, String>(0)?,
+                    row.get::<usize, Vec<u8>>(1)?,
+                    row.get::<usize, String>(2)?,
+                    row.get::<usize, Vec<u8>>(3)?,
+                    row.get::<usize, Option<String>>(4)?,
+                ))
+            })
+            .map_err(|e| Error::emit("Querying leases".into(), e))?;
+
+        let mut leases = vec![];
+        for row in rows {
+            let (address, chaddr, serverid, nonce, leasepolicy) =
+                row.map_err(|e| Error::emit("Reading lease".into(), e))?;
+            if let Some(policy) = policy {
+                match leasepolicy {
+                    Some(p) if p == policy || p.starts_with(&format!("{}.", policy)) => (),
+                    _ => continue,
+                }
+            }
+            leases.push(ForceRenewInfo {
+                address: address
+                    .parse()
+                    .map_err(|_| Error::CorruptDatabase(format!("Invalid address {}", address)))?,
+                chaddr,
+                serverid: serverid.parse().map_err(|_| {
+                    Error::CorruptDatabase(format!("Invalid server id {}", serverid))
+                })?,
+                nonce,
+            });
+        }
+        leases.sort_by_key(|lease| u32::from(lease.address));
+        Ok(leases)
+    }
+
+    /// Updates the policy recorded with each lease after the configuration has changed.  Leases
+    /// from policies that moved (as (old path, new path)) follow them, and leases from policies
+    /// that changed (or their subpolicies) no longer have a policy until the client renews.
+    pub fn update_policies(
+        &mut self,
+        moved: &[(String, String)],
+        changed: &[String],
+    ) -> Result<(), Error> {
+        let tx = self
+            .conn
+            .transaction()
+            .map_err(|e| Error::emit("Starting policy update".into(), e))?;
+        /* Leases are updated one at a time, as policies can swap places */
+        let leases = {
+            let mut stmt = tx
+                .prepare("SELECT address, policy FROM leases WHERE policy IS NOT NULL")
+                .map_err(|e| Error::emit("Querying lease policies".into(), e))?;
+            let rows = stmt
+                .query_map(rusqlite::params![], |row| {
+                    Ok((row.get::<usize, String>(0)?, row.get::<usize, String>(1)?))
+                })
+                .map_err(|e| Error::emit("Querying lease policies".into(), e))?;
+            rows.collect::<Result<Vec<_>, _>>()
+                .map_err(|e| Error::emit("Reading lease policy".into(), e))?
+        };
+        for (address, policy) in leases {
+            let newpolicy = if changed
+                .iter()
+                .any(|p| policy == *p || policy.starts_with(&format!("{}.", p)))
+            {
+                None
+            } else if let Some((_, new)) = moved.iter().find(|(old, _)| *old == policy) {
+                Some(new)
+            } else {
+                continue;
+            };
+            tx.execute(
+                "UPDATE leases SET policy = ?1 WHERE address = ?2",
+                rusqlite::params![newpolicy, address],
+            )
+            .map_err(|e| Error::emit("Updating lease policy".into(), e))?;
+        }
+        tx.commit()
+            .map_err(|e| Error::emit("Committing policy update".into(), e))
+    }
+
+    fn is_allocated(&self, address: std::net::Ipv4Addr, ts: u32) -> Result<bool, Error> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT
+                      true
+                     FROM
+                      leases
+                     WHERE expiry >= ?1
+                     AND address = ?2",
+                rusqlite::params![ts, address.to_string()],
+                |_row| Ok(Some(())),
+            )
+            .or_else(map_no_row_to_none)?
+            .is_some())
+    }
+
     fn select_requested_address(
         &mut self,
         requested: std::net::Ipv4Addr,
@@ -203,28 +700,38 @@ impl Pool {
          *
          * o The client's current address as recorded in the client's current
          *   binding, ELSE */
-        if let Some(lease) = self
-            .conn
-            .query_row(
-                "SELECT
-               address,
-               expiry,
-               start
-             FROM
-               leases
-             WHERE clientid = ?1
-             AND expiry > ?2",
-                rusqlite::params![clientid, ts as u32],
-                |row| {
-                    Ok(Some((
+
+        /* A client can have a binding in more than one pool (eg if it moves between networks),
+         * so look for the one that's in this pool.
+         */
+        let current = {
+            let mut stmt = self
+                .conn
+                .prepare(
+                    "SELECT
+                       address,
+                       expiry,
+                       start
+                     FROM
+                       leases
+                     WHERE clientid = ?1
+                     AND expiry > ?2
+                     ORDER BY expiry DESC",
+                )
+                .map_err(|e| Error::emit("Querying current leases".into(), e))?;
+            let rows = stmt
+                .query_map(rusqlite::params![clientid, ts as u32], |row| {
+                    Ok((
                         row.get::<usize, String>(0)?,
                         row.get::<usize, u32>(1)?,
                         row.get::<usize, u32>(2)?,
-                    )))
-                },
-            )
-            .or_else(map_no_row_to_none)?
-        {
+                    ))
+                })
+                .map_err(|e| Error::emit("Querying current leases".into(), e))?;
+            rows.collect::<Result<Vec<_>, _>>()
+                .map_err(|e| Error::emit("Reading current leases".into(), e))?
+        };
+        for lease in current {
             if let Ok(ip) = lease.0.parse::<std::net::Ipv4Addr>() {
                 if addresses.contains(&ip) {
                     println!("Reusing existing lease: {:?}", lease);
@@ -246,30 +753,34 @@ impl Pool {
          * expired or released) binding, if that address is in the server's
          * pool of available addresses and not already allocated, ELSE */
 
-        if let Some(lease) = self
-            .conn
-            .query_row(
-                "SELECT
-               address,
-               start,
-               max(expiry)
-             FROM
-               leases
-             WHERE clientid = ?1
-             GROUP BY 1",
-                rusqlite::params![clientid],
-                |row| {
-                    Ok(Some((
+        /* Bindings that have been swept live in the lease history, so check there too, most
+         * recent first.
+         */
+        let previous = {
+            let mut stmt = self
+                .conn
+                .prepare(
+                    "SELECT address, start, expiry FROM leases WHERE clientid = ?1
+                     UNION ALL
+                     SELECT address, start, expiry FROM lease_history WHERE clientid = ?1
+                     ORDER BY expiry DESC",
+                )
+                .map_err(|e| Error::emit("Querying previous leases".into(), e))?;
+            let rows = stmt
+                .query_map(rusqlite::params![clientid], |row| {
+                    Ok((
                         row.get::<usize, String>(0)?,
                         row.get::<usize, u32>(1)?,
                         row.get::<usize, u32>(2)?,
-                    )))
-                },
-            )
-            .or_else(map_no_row_to_none)?
-        {
+                    ))
+                })
+                .map_err(|e| Error::emit("Querying previous leases".into(), e))?;
+            rows.collect::<Result<Vec<_>, _>>()
+                .map_err(|e| Error::emit("Reading previous leases".into(), e))?
+        };
+        for lease in previous {
             if let Ok(ip) = lease.0.parse::<std::net::Ipv4Addr>() {
-                if addresses.contains(&ip) {
+                if addresses.contains(&ip) && !self.is_allocated(ip, ts as u32)? {
                     println!("Reviving old lease: {:?}", lease);
                     return Ok(Lease {
                         ip,
@@ -277,7 +788,9 @@ impl Pool {
                          * the lease time.  This means transient devices get short leases, and
                          * devices that are more permanent get longer leases.
                          */
-                        expire: std::time::Duration::from_secs(2 * (lease.2 - lease.1) as u64),
+                        expire: std::time::Duration::from_secs(
+                            2 * lease.2.saturating_sub(lease.1) as u64,
+                        ),
                     });
                 }
             }
@@ -304,6 +817,7 @@ impl Pool {
     pub fn allocate_address(
         &mut self,
         clientid: &[u8],
+        client: &ClientInfo,
         requested: Option<std::net::Ipv4Addr>,
         addresses: &PoolAddresses,
         min_expire_time: std::time::Duration,
@@ -311,6 +825,42 @@ impl Pool {
     ) -> Result<Lease, Error> {
         let lease = self.select_address(clientid, requested, addresses)?;
 
+        self.commit_lease(clientid, client, lease, min_expire_time, max_expire_time)
+    }
+
+    /// Allocate the address the client has asked for, but only if that is the address we would
+    /// choose for them anyway.  This is used when a client is renewing/rebinding/rebooting, where
+    /// the client can't be given a different address.
+    pub fn confirm_address(
+        &mut self,
+        clientid: &[u8],
+        client: &ClientInfo,
+        requested: std::net::Ipv4Addr,
+        addresses: &PoolAddresses,
+        min_expire_time: std::time::Duration,
+        max_expire_time: std::time::Duration,
+    ) -> Result<Lease, Error> {
+        let lease = self.select_address(clientid, Some(requested), addresses)?;
+
+        if lease.ip != requested {
+            println!(
+                "Requested {:?}, but client should be using {:?}",
+                requested, lease.ip
+            );
+            return Err(Error::NoAssignableAddress);
+        }
+
+        self.commit_lease(clientid, client, lease, min_expire_time, max_expire_time)
+    }
+
+    fn commit_lease(
+        &mut self,
+        clientid: &[u8],
+        client: &ClientInfo,
+        lease: Lease,
+        min_expire_time: std::time::Duration,
+        max_expire_time: std::time::Duration,
+    ) -> Result<Lease, Error> {
         let lease = Lease {
             expire: std::cmp::min(
                 std::cmp::max(lease.expire, min_expire_time),
@@ -327,20 +877,121 @@ impl Pool {
         self.conn
             .execute(
                 "INSERT OR REPLACE
-                 INTO leases (address, clientid, start, expiry)
-                 VALUES (?1, ?2, ?3, ?4)",
+                 INTO leases (address, clientid, start, expiry, chaddr, hostname, vendorclass, policy)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
                 rusqlite::params![
                     lease.ip.to_string(),
                     clientid,
                     ts as u32,
-                    (ts + lease.expire.as_secs()) as u32
+                    (ts + lease.expire.as_secs()) as u32,
+                    client.chaddr,
+                    client.hostname,
+                    client.vendor_class,
+                    client.policy,
                 ],
             )
-            .expect("Updating lease database failed"); /* Better error handling */
+            .map_err(|e| Error::emit("Updating lease database".into(), e))?;
 
         Ok(lease)
     }
 
+    /// Releases the client's lease on `address`.  Returns whether the client had an active lease
+    /// on it to release.
+    pub fn release_address(
+        &mut self,
+        address: std::net::Ipv4Addr,
+        clientid: &[u8],
+    ) -> Result<bool, Error> {
+        let ts = std::time::SystemTime::now()
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .expect("clock failure")
+            .as_secs();
+
+        /* We don't forget the lease, we move it to the lease history as having just expired.  This
+         * way we still remember the client's previous binding, and can hand them back the same
+         * address if it's still available next time they ask, but it isn't seen as expiring again
+         * later when expired leases are swept.
+         */
+        let tx = self
+            .conn
+            .transaction()
+            .map_err(|e| Error::emit("Starting lease release".into(), e))?;
+        let released = tx
+            .execute(
+                "INSERT INTO lease_history
+                   (address, clientid, chaddr, hostname, vendorclass, policy, start, expiry)
+                 SELECT address, clientid, chaddr, hostname, vendorclass, policy, start, ?1 - 1
+                 FROM leases
+                 WHERE address = ?2
+                 AND clientid = ?3
+                 AND expiry >= ?1",
+                rusqlite::params![ts as u32, address.to_string(), clientid],
+            )
+            .map_err(|e| Error::emit("Releasing lease".into(), e))?;
+        tx.execute(
+            "DELETE FROM leases
+             WHERE address = ?2
+             AND clientid = ?3
+             AND expiry >= ?1",
+            rusqlite::params![ts as u32, address.to_string(), clientid],
+        )
+        .map_err(|e| Error::emit("Releasing lease".into(), e))?;
+        tx.commit()
+            .map_err(|e| Error::emit("Committing lease release".into(), e))?;
+
+        if released == 0 {
+            println!("No active lease for {:?} to release", address);
+        }
+
+        Ok(released > 0)
+    }
+
+    pub fn decline_address(
+        &mut self,
+        address: std::net::Ipv4Addr,
+        clientid: &[u8],
+        quarantine: std::time::Duration,
+    ) -> Result<(), Error> {
+        let ts = std::time::SystemTime::now()
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .expect("clock failure")
+            .as_secs();
+
+        /* The address is in use by something we don't know about.  Take the lease away from the
+         * client, and hold it without any owner until the quarantine expires, so nobody else gets
+         * handed the same conflicting address.
+         */
+        let updated = self
+            .conn
+            .execute(
+                "UPDATE leases
+                 SET chaddr = NULL,
+                     clientid = NULL,
+                     hostname = NULL,
+                     vendorclass = NULL,
+                     policy = NULL,
+                     serverid = NULL,
+                     forcerenew_nonce = NULL,
+                     start = ?1,
+                     expiry = ?2
+                 WHERE address = ?3
+                 AND clientid = ?4",
+                rusqlite::params![
+                    ts as u32,
+                    (ts + quarantine.as_secs()) as u32,
+                    address.to_string(),
+                    clientid
+                ],
+            )
+            .map_err(|e| Error::emit("Quarantining declined address".into(), e))?;
+
+        if updated == 0 {
+            println!("No lease for {:?} to decline", address);
+        }
+
+        Ok(())
+    }
+
     #[cfg(test)]
     fn reserve_address_internal(
         &mut self,
@@ -377,6 +1028,54 @@ impl Pool {
     }
 }
 
+/* Runs a query that returns the columns address, chaddr, clientid, start, expiry, hostname,
+ * vendorclass and policy.
+ */
+fn query_leases(
+    conn: &rusqlite::Connection,
+    sql: &str,
+    params: &[&dyn rusqlite::ToSql],
+) -> Result<Vec<LeaseInfo>, Error> {
+    let mut stmt = conn
+        .prepare(sql)
+        .map_err(|e| Error::emit("Querying leases".into(), e))?;
+    let rows = stmt
+        .query_map(params, |row| {
+            Ok((
+                row.get::<usize, String>(0)?,
+                row.get::<usize, Option<Vec<u8>>>(1)?,
+                row.get::<usize, Option<Vec<u8>>>(2)?,
+                row.get::<usize, u32>(3)?,
+                row.get::<usize, u32>(4)?,
+                row.get::<usize, Option<String>>(5)?,
+                row.get::<usize, Option<String>>(6)?,
+                row.get::<usize, Option<String>>(7)?,
+            ))
+        })
+        .map_err(|e| Error::emit("Querying leases".into(), e))?;
+
+    let mut leases = vec![];
+    for row in rows {
+        let (address, chaddr, clientid, start, expiry, hostname, vendor_class, policy) =
+            row.map_err(|e| Error::emit("Reading lease".into(), e))?;
+        leases.push(LeaseInfo {
+            address: address
+                .parse()
+                .map_err(|_| Error::CorruptDatabase(format!("Invalid address {}", address)))?,
+            clientid,
+            client: ClientInfo {
+                chaddr,
+                hostname,
+                vendor_class,
+                policy,
+            },
+            start: start.into(),
+            expiry: expiry.into(),
+        });
+    }
+    Ok(leases)
+}
+
 fn map_no_row_to_none<T>(e: rusqlite::Error) -> Result<Option<T>, Error> {
     if e == rusqlite::Error::QueryReturnedNoRows {
         Ok(None)
@@ -394,6 +1093,7 @@ fn smoke_test() {
     addrpool.insert("192.168.0.102".parse().unwrap());
     p.allocate_address(
         b"client",
+        &Default::default(),
         None,
         &addrpool,
         DEFAULT_MIN_LEASE,
@@ -410,6 +1110,7 @@ fn empty_pool() {
     assert_eq!(
         p.allocate_address(
             b"client",
+            &Default::default(),
             None,
             &addrpool,
             DEFAULT_MIN_LEASE,
@@ -433,6 +1134,7 @@ fn reacquire_lease() {
     let lease = p
         .allocate_address(
             b"client",
+            &Default::default(),
             Some(requested),
             &addrpool,
             DEFAULT_MIN_LEASE,
@@ -460,6 +1162,7 @@ fn reacquire_expired_lease() {
     let lease = p
         .allocate_address(
             b"client",
+            &Default::default(),
             Some(requested),
             &addrpool,
             DEFAULT_MIN_LEASE,
@@ -487,6 +1190,7 @@ fn acquire_requested_address_success() {
     let lease = p
         .allocate_address(
             b"client",
+            &Default::default(),
             Some(requested),
             &addrpool,
             DEFAULT_MIN_LEASE,
@@ -512,6 +1216,7 @@ fn acquire_requested_address_in_use() {
     let lease = p
         .allocate_address(
             b"client",
+            &Default::default(),
             Some(requested),
             &addrpool,
             DEFAULT_MIN_LEASE,
@@ -542,6 +1247,138 @@ fn acquire_requested_address_invalid() {
     assert_ne!(lease.ip, requested);
 }
 
+#[test]
+fn dont_hand_out_declined_address() {
+    let mut p = Pool::new_in_memory().expect("Failed to create in memory pools");
+
+    let mut addrpool: PoolAddresses = Default::default();
+    let declined = "192.168.0.100".parse().unwrap();
+    addrpool.insert(declined);
+
+    let lease = p
+        .allocate_address(
+            b"client",
+            &Default::default(),
+            None,
+            &addrpool,
+            DEFAULT_MIN_LEASE,
+            DEFAULT_MAX_LEASE,
+        )
+        .expect("Failed to allocate address");
+    assert_eq!(lease.ip, declined);
+
+    p.decline_address(declined, b"client", DEFAULT_DECLINE_QUARANTINE)
+        .expect("Failed to decline address");
+
+    /* Neither the declining client, nor anyone else should be offered the address */
+    for client in &[&b"client"[..], &b"other-client"[..]] {
+        assert_eq!(
+            p.allocate_address(
+                client,
+                &Default::default(),
+                Some(declined),
+                &addrpool,
+                DEFAULT_MIN_LEASE,
+                DEFAULT_MAX_LEASE,
+            )
+            .expect_err("Allocated a quarantined address"),
+            Error::NoAssignableAddress
+        );
+    }
+}
+
+#[test]
+fn confirm_address_mismatch() {
+    /* A client that already has a binding shouldn't be able to confirm some other address */
+    let mut p = Pool::new_in_memory().expect("Failed to create in memory pools");
+
+    let mut addrpool: PoolAddresses = Default::default();
+    let current = "192.168.0.100".parse().unwrap();
+    let other = "192.168.0.101".parse().unwrap();
+    addrpool.insert(current);
+    addrpool.insert(other);
+    p.reserve_address(b"client", current);
+
+    assert_eq!(
+        p.confirm_address(
+            b"client",
+            &Default::default(),
+            other,
+            &addrpool,
+            DEFAULT_MIN_LEASE,
+            DEFAULT_MAX_LEASE
+        )
+        .expect_err("Confirmed an address the client doesn't own"),
+        Error::NoAssignableAddress
+    );
+
+    let lease = p
+        .confirm_address(
+            b"client",
+            &Default::default(),
+            current,
+            &addrpool,
+            DEFAULT_MIN_LEASE,
+            DEFAULT_MAX_LEASE,
+        )
+        .expect("Failed to confirm address");
+    assert_eq!(lease.ip, current);
+}
+
+#[test]
+fn client_with_leases_in_two_pools() {
+    /* A client that has moved between networks can hold a lease in each pool, and must be able
+     * to keep both.
+     */
+    let mut p = Pool::new_in_memory().expect("Failed to create in memory pools");
+
+    let mut pool_a: PoolAddresses = Default::default();
+    pool_a.insert("192.0.2.1".parse().unwrap());
+    pool_a.insert("192.0.2.2".parse().unwrap());
+    let mut pool_b: PoolAddresses = Default::default();
+    pool_b.insert("198.51.100.1".parse().unwrap());
+    pool_b.insert("198.51.100.2".parse().unwrap());
+
+    /* The leases expire at different times and the newest is renewed first, so whichever order
+     * the client's leases are looked up in, one of the renewals isn't for the first one found.
+     */
+    let short_lease = std::time::Duration::from_secs(600);
+    let allocate = |p: &mut Pool, addrpool: &PoolAddresses, lease_time| {
+        p.allocate_address(
+            b"client",
+            &Default::default(),
+            None,
+            addrpool,
+            lease_time,
+            lease_time,
+        )
+        .expect("Failed to allocate address")
+    };
+    let lease_a = allocate(&mut p, &pool_a, short_lease);
+    let lease_b = allocate(&mut p, &pool_b, DEFAULT_MAX_LEASE);
+
+    for (lease, addrpool, lease_time) in &[
+        (&lease_b, &pool_b, DEFAULT_MAX_LEASE),
+        (&lease_a, &pool_a, short_lease),
+    ] {
+        let confirmed = p
+            .confirm_address(
+                b"client",
+                &Default::default(),
+                lease.ip,
+                addrpool,
+                *lease_time,
+                *lease_time,
+            )
+            .expect("Failed to renew address");
+        assert_eq!(confirmed.ip, lease.ip);
+    }
+
+    /* Asking again in either pool gets the same address, not a second one */
+    assert_eq!(allocate(&mut p, &pool_a, short_lease).ip, lease_a.ip);
+    assert_eq!(allocate(&mut p, &pool_b, DEFAULT_MAX_LEASE).ip, lease_b.ip);
+}
+
 #[test]
 fn dont_hand_out_old_stale_lease() {
     /* If this client previously had an address that is no longer in the pool,
@@ -562,3 +1399,372 @@ fn dont_hand_out_old_stale_lease() {
     /* Do not assigned the old_reserved address! */
     assert_ne!(lease.ip, old_reserved);
 }
+
+#[test]
+fn get_leases() {
+    let mut p = Pool::new_in_memory().expect("Failed to create in memory pools");
+    let mut addrpool: PoolAddresses = Default::default();
+    addrpool.insert("192.0.2.1".parse().unwrap());
+    addrpool.insert("198.51.100.1".parse().unwrap());
+    let mut ethernet_clientid = vec![1];
+    ethernet_clientid.extend_from_slice(&[0x00, 0x00, 0x5E, 0x00, 0x53, 0x01]);
+    for clientid in &[ethernet_clientid.as_slice(), b"Other Client"] {
+        p.allocate_address(
+            clientid,
+            &Default::default(),
+            None,
+            &addrpool,
+            DEFAULT_MIN_LEASE,
+            DEFAULT_MAX_LEASE,
+        )
+        .expect("Failed to allocate address");
+    }
+
+    let leases = p
+        .get_leases(&Default::default())
+        .expect("Failed to get leases");
+    assert_eq!(leases.len(), 2);
+    assert!(u32::from(leases[0].address) < u32::from(leases[1].address));
+
+    let leases = p
+        .get_leases(&LeaseFilter {
+            chaddr: Some(vec![0x00, 0x00, 0x5E, 0x00, 0x53, 0x01]),
+            ..Default::default()
+        })
+        .expect("Failed to get leases");
+    assert_eq!(leases.len(), 1);
+    assert_eq!(leases[0].clientid, Some(ethernet_clientid));
+
+    let leases = p
+        .get_leases(&LeaseFilter {
+            subnet: Some(crate::net::Ipv4Subnet::new("198.51.100.0".parse().unwrap(), 24).unwrap()),
+            ..Default::default()
+        })
+        .expect("Failed to get leases");
+    assert_eq!(leases.len(), 1);
+    assert_eq!(
+        leases[0].address,
+        "198.51.100.1".parse::<std::net::Ipv4Addr>().unwrap()
+    );
+
+    /* Released leases are only shown when asking for expired leases too */
+    p.release_address(leases[0].address, leases[0].clientid.as_ref().unwrap())
+        .expect("Failed to release address");
+    assert_eq!(
+        p.get_leases(&Default::default())
+            .expect("Failed to get leases")
+            .len(),
+        1
+    );
+    assert_eq!(
+        p.get_leases(&LeaseFilter {
+            include_expired: true,
+            ..Default::default()
+        })
+        .expect("Failed to get leases")
+        .len(),
+        2
+    );
+}
+
+#[test]
+fn open_database_paths() {
+    Pool::open(std::path::Path::new(":memory:")).expect("Failed to open in memory database");
+    match Pool::open(std::path::Path::new("/nonexistent/erbium/leases.sqlite")) {
+        Err(Error::InvalidDatabasePath(_)) => (),
+        Err(e) => panic!("Unexpected error {}", e),
+        Ok(_) => panic!("Opened a database in a nonexistent directory"),
+    }
+}
+
+#[test]
+fn open_database_read_only() {
+    let dir = crate::test_util::TestDir::new("pool");
+    let path = dir.join("leases.sqlite");
+    /* Opening a database read only never creates it */
+    assert!(Pool::open_read_only(&path).is_err());
+    assert!(!path.exists());
+
+    let mut p = Pool::open(&path).expect("Failed to create database");
+    let mut addrpool: PoolAddresses = Default::default();
+    addrpool.insert("192.0.2.1".parse().unwrap());
+    p.allocate_address(
+        b"client",
+        &Default::default(),
+        None,
+        &addrpool,
+        DEFAULT_MIN_LEASE,
+        DEFAULT_MAX_LEASE,
+    )
+    .expect("Failed to allocate address");
+    drop(p);
+
+    let mut p = Pool::open_read_only(&path).expect("Failed to open database read only");
+    let leases = p.get_leases(&Default::default());
+    let written = p.allocate_address(
+        b"other",
+        &Default::default(),
+        None,
+        &addrpool,
+        DEFAULT_MIN_LEASE,
+        DEFAULT_MAX_LEASE,
+    );
+    assert_eq!(leases.expect("Failed to get leases").len(), 1);
+    assert!(written.is_err());
+}
+
+#[cfg(test)]
+fn get_all_leases(p: &Pool) -> Vec<LeaseInfo> {
+    p.get_leases(&LeaseFilter {
+        include_expired: true,
+        ..Default::default()
+    })
+    .expect("Failed to get leases")
+}
+
+#[test]
+fn new_database_is_latest_schema() {
+    let p = Pool::new_in_memory().expect("Failed to create in memory pools");
+    assert_eq!(read_schema_version(&p.conn).unwrap(), MIGRATIONS.len());
+    /* Opening it again doesn't try and reapply any migrations, or write to it at all */
+    p.conn
+        .execute_batch("PRAGMA query_only = ON;")
+        .expect("Failed to make database read only");
+    let p = p.setup_db().expect("Failed to reopen database");
+    assert_eq!(read_schema_version(&p.conn).unwrap(), MIGRATIONS.len());
+}
+
+#[test]
+fn read_only_open_does_not_upgrade() {
+    let dir = crate::test_util::TestDir::new("schema");
+    let unversioned = dir.join("unversioned.sqlite");
+    rusqlite::Connection::open(&unversioned)
+        .and_then(|conn| conn.execute_batch(MIGRATIONS[0]))
+        .expect("Failed to create unversioned database");
+    let versioned = dir.join("versioned.sqlite");
+    let mut conn = rusqlite::Connection::open(&versioned).expect("Failed to create database");
+    migrate(&mut conn, &MIGRATIONS[..1]).expect("Failed to create version 1 database");
+    drop(conn);
+
+    let results = [
+        Pool::open_read_only(&unversioned).map(|_| ()),
+        Pool::open_read_only(&versioned).map(|_| ()),
+    ];
+    let untouched = rusqlite::Connection::open(&unversioned)
+        .map_err(|e| Error::emit("Reopening database".into(), e))
+        .and_then(|conn| table_exists(&conn, "schema_version"));
+    for result in &results {
+        match result {
+            Err(Error::OutdatedDatabase(_)) => (),
+            x => panic!("Unexpected result {:?}", x),
+        }
+    }
+    assert_eq!(untouched, Ok(false));
+}
+
+#[test]
+fn upgrade_unversioned_database() {
+    /* A database created before schema versioning */
+    let conn = rusqlite::Connection::open_in_memory().expect("Failed to create database");
+    conn.execute_batch(MIGRATIONS[0])
+        .expect("Failed to create old table");
+    conn.execute(
+        "INSERT INTO leases (address, clientid, start, expiry) VALUES ('192.0.2.1', x'00', 0, 0)",
+        rusqlite::params![],
+    )
+    .expect("Failed to insert old lease");
+
+    let p = Pool::new_with_conn(conn).expect("Failed to upgrade database");
+    assert_eq!(read_schema_version(&p.conn).unwrap(), MIGRATIONS.len());
+    let leases = get_all_leases(&p);
+    assert_eq!(leases.len(), 1);
+    assert_eq!(leases[0].clientid, Some(vec![0]));
+    assert_eq!(leases[0].client, Default::default());
+}
+
+#[test]
+fn upgrade_versioned_database() {
+    let mut conn = rusqlite::Connection::open_in_memory().expect("Failed to create database");
+    migrate(&mut conn, &MIGRATIONS[..1]).expect("Failed to create version 1 database");
+    assert_eq!(read_schema_version(&conn).unwrap(), 1);
+
+    let p = Pool::new_with_conn(conn).expect("Failed to upgrade database");
+    assert_eq!(read_schema_version(&p.conn).unwrap(), MIGRATIONS.len());
+    assert_eq!(get_all_leases(&p), vec![]);
+}
+
+#[test]
+fn failed_upgrade_is_rolled_back() {
+    let mut conn = rusqlite::Connection::open_in_memory().expect("Failed to create database");
+    migrate(&mut conn, &MIGRATIONS[..1]).expect("Failed to create version 1 database");
+
+    let broken = [MIGRATIONS[0], MIGRATIONS[1], "THIS IS NOT SQL;"];
+    assert!(migrate(&mut conn, &broken).is_err());
+    assert_eq!(read_schema_version(&conn).unwrap(), 1);
+    /* The columns from version 2 shouldn't have been added */
+    assert!(conn.prepare("SELECT hostname FROM leases").is_err());
+}
+
+#[test]
+fn refuse_newer_database() {
+    let mut conn = rusqlite::Connection::open_in_memory().expect("Failed to create database");
+    migrate(&mut conn, MIGRATIONS).expect("Failed to create database");
+    conn.execute(
+        "UPDATE schema_version SET version = ?1",
+        rusqlite::params![MIGRATIONS.len() as u32 + 1],
+    )
+    .expect("Failed to set schema version");
+    match Pool::new_with_conn(conn) {
+        Err(Error::CorruptDatabase(_)) => (),
+        Err(e) => panic!("Unexpected error {}", e),
+        Ok(_) => panic!("Opened a database from the future"),
+    }
+}
+
+#[test]
+fn read_only_database() {
+    let mut p = Pool::new_read_only_for_test().expect("Failed to create in memory pools");
+    let mut addrpool: PoolAddresses = Default::default();
+    addrpool.insert("192.0.2.1".parse().unwrap());
+    match p.allocate_address(
+        b"client",
+        &Default::default(),
+        None,
+        &addrpool,
+        DEFAULT_MIN_LEASE,
+        DEFAULT_MAX_LEASE,
+    ) {
+        Err(Error::DbError(_, _)) => (),
+        x => panic!("Unexpected result {:?}", x),
+    }
+}
+
+#[test]
+fn release_lease() {
+    let mut p = Pool::new_in_memory().expect("Failed to create in memory pools");
+    let address = "192.0.2.1".parse().unwrap();
+    p.allocate_address(
+        b"client",
+        &Default::default(),
+        None,
+        &std::iter::once(address).collect(),
+        DEFAULT_MIN_LEASE,
+        DEFAULT_MAX_LEASE,
+    )
+    .expect("Failed to allocate address");
+
+    /* Only the client holding the lease can release it, and only once */
+    assert_eq!(p.release_address(address, b"other"), Ok(false));
+    assert_eq!(p.release_address(address, b"client"), Ok(true));
+    assert_eq!(p.release_address(address, b"client"), Ok(false));
+
+    /* A released lease is already history, so isn't reported as expiring */
+    assert_eq!(p.expire_leases(DEFAULT_LEASE_HISTORY), Ok(vec![]));
+    assert_eq!(get_all_leases(&p).len(), 1);
+}
+
+#[test]
+fn update_policies() {
+    let mut p = Pool::new_in_memory().expect("Failed to create in memory pools");
+    let policies = ["0", "0.1", "1", "2", "3"];
+    for (i, policy) in policies.iter().enumerate() {
+        let address: std::net::Ipv4Addr = format!("192.0.2.{}", i + 1).parse().unwrap();
+        p.allocate_address(
+            policy.as_bytes(),
+            &ClientInfo {
+                policy: Some(policy.to_string()),
+                ..Default::default()
+            },
+            None,
+            &std::iter::once(address).collect(),
+            DEFAULT_MIN_LEASE,
+            DEFAULT_MAX_LEASE,
+        )
+        .expect("Failed to allocate address");
+    }
+    /* Policies 1 and 2 swap places, and policy 0 changes */
+    p.update_policies(
+        &[("1".into(), "2".into()), ("2".into(), "1".into())],
+        &["0".into()],
+    )
+    .expect("Failed to update policies");
+    assert_eq!(
+        get_all_leases(&p)
+            .into_iter()
+            .map(|lease| lease.client.policy)
+            .collect::<Vec<_>>(),
+        vec![
+            None,
+            None,
+            Some("2".to_string()),
+            Some("1".to_string()),
+            Some("3".to_string())
+        ]
+    );
+}
+
+#[test]
+fn expire_leases() {
+    let mut p = Pool::new_in_memory().expect("Failed to create in memory pools");
+    let active = "192.168.0.100".parse().unwrap();
+    let expired = "192.168.0.101".parse().unwrap();
+    p.reserve_address(b"client1", active);
+    p.reserve_expired_address(b"client2", expired);
+
+    let swept = p
+        .expire_leases(DEFAULT_LEASE_HISTORY)
+        .expect("Failed to expire leases");
+    assert_eq!(swept.len(), 1);
+    assert_eq!(swept[0].address, expired);
+    assert_eq!(swept[0].clientid, Some(b"client2".to_vec()));
+
+    /* The expired lease is now only in the history */
+    let leases = p
+        .get_leases(&Default::default())
+        .expect("Failed to get leases");
+    assert_eq!(leases.len(), 1);
+    assert_eq!(leases[0].address, active);
+    assert_eq!(get_all_leases(&p).len(), 2);
+
+    /* Sweeping again finds nothing new */
+    assert_eq!(p.expire_leases(DEFAULT_LEASE_HISTORY), Ok(vec![]));
+    p.vacuum().expect("Failed to vacuum database");
+
+    /* RFC2131 Section 4.3.1: The client's previous address as recorded in the client's (now
+     * expired or released) binding
+     */
+    let mut addrpool: PoolAddresses = Default::default();
+    for i in 100..110 {
+        addrpool.insert(std::net::Ipv4Addr::new(192, 168, 0, i));
+    }
+    let lease = p
+        .select_address(b"client2", None, &addrpool)
+        .expect("Failed to allocate address");
+    assert_eq!(lease.ip, expired);
+}
+
+#[test]
+fn lease_history_is_bounded() {
+    let mut p = Pool::new_in_memory().expect("Failed to create in memory pools");
+    for i in 0..5 {
+        p.conn
+            .execute(
+                "INSERT INTO leases (address, clientid, start, expiry) VALUES (?1, ?2, 0, ?3)",
+                rusqlite::params![format!("192.168.0.{}", 100 + i), b"client".to_vec(), i],
+            )
+            .expect("Failed to add lease");
+    }
+    p.reserve_expired_address(b"other", "192.168.0.200".parse().unwrap());
+    assert_eq!(p.expire_leases(2).unwrap().len(), 6);
+
+    /* Only the most recent two are kept for each client */
+    let mut history: Vec<_> = get_all_leases(&p)
+        .iter()
+        .map(|lease| lease.address.to_string())
+        .collect();
+    history.sort();
+    assert_eq!(
+        history,
+        vec!["192.168.0.103", "192.168.0.104", "192.168.0.200"]
+    );
+}
@@ -0,0 +1,211 @@
+/*   Copyright 2020 Perry Lorier
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *  SPDX-License-Identifier: Apache-2.0
+ *
+ *  Sections quoted from RFCs are covered by the terms specified in RFC3978.
+ *
+ *  Checks if an address is already in use before offering it to a client.
+ */
+
+use crate::net::packet;
+use crate::net::raw;
+use nix::libc;
+use nix::sys::socket::{InetAddr, LinkAddr, SockAddr};
+use rand::Rng;
+use std::net;
+
+use super::config::Probe;
+
+pub const DEFAULT_PROBE_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(500);
+
+const ARP_REQUEST: u16 = 1;
+const ICMP_ECHO_REPLY: u8 = 0;
+
+fn link_addr(ifindex: u32, protocol: u16, dst: [u8; 6]) -> SockAddr {
+    let mut sll_addr = [0; 8];
+    sll_addr[..6].copy_from_slice(&dst);
+    SockAddr::Link(LinkAddr(libc::sockaddr_ll {
+        sll_family: libc::AF_PACKET as u16,
+        sll_protocol: protocol.to_be(),
+        sll_ifindex: ifindex as i32,
+        sll_hatype: 0,
+        sll_pkttype: 0,
+        sll_halen: 6,
+        sll_addr,
+    }))
+}
+
+/* RFC5227 Section 2.1.1: if the host receives any ARP packet (Request *or* Reply) on the
+ * interface where the probe is being performed, where the packet's 'sender IP address' is the
+ * address being probed for, then this host MUST treat this address as being in use by some
+ * other host.
+ *
+ * We also see our own probe go out, so ignore anything we sent.  The client we're offering the
+ * address to may already be using it (eg it's rebooting, or we've forgotten its lease), so it
+ * answering isn't a conflict either.
+ */
+fn is_arp_conflict(pkt: &[u8], ourmac: &[u8; 6], clientmac: &[u8], addr: net::Ipv4Addr) -> bool {
+    if pkt.len() < 28 || pkt[0..6] != [0x00, 0x01, 0x08, 0x00, 6, 4] {
+        return false;
+    }
+    pkt[8..14] != ourmac[..] && pkt[8..14] != *clientmac && pkt[14..18] == addr.octets()
+}
+
+/* The socket gives us the IPv4 header as well as the ICMP message */
+fn is_echo_reply(pkt: &[u8], id: u16, addr: net::Ipv4Addr) -> bool {
+    if pkt.len() < 20 {
+        return false;
+    }
+    let ihl = ((pkt[0] & 0x0F) as usize) * 4;
+    if pkt.len() < ihl + 8 || pkt[12..16] != addr.octets() {
+        return false;
+    }
+    let icmp = &pkt[ihl..];
+    icmp[0] == ICMP_ECHO_REPLY && icmp[4..6] == id.to_be_bytes()
+}
+
+/* RFC5227 Section 2.1.1: The host ... [sends] an ARP Request packet, broadcast on the local
+ * link.  The 'sender hardware address' field MUST contain the hardware address of the interface
+ * sending the packet.  The 'sender IP address' field MUST be set to all zeroes ... The 'target
+ * hardware address' field is ignored and SHOULD be set to all zeroes.  The 'target IP address'
+ * field MUST be set to the address being probed.
+ */
+async fn probe_arp(
+    ifindex: u32,
+    ourmac: &[u8; 6],
+    clientmac: &[u8],
+    addr: net::Ipv4Addr,
+    timeout: std::time::Duration,
+) -> Result<bool, std::io::Error> {
+    let sock = raw::RawSocket::new_with_protocol(
+        libc::AF_PACKET,
+        libc::SOCK_DGRAM,
+        i32::from((libc::ETH_P_ARP as u16).to_be()),
+    )?;
+    sock.bind(&link_addr(ifindex, libc::ETH_P_ARP as u16, [0; 6]))?;
+    let probe = packet::Fragment::new_arp(
+        ARP_REQUEST,
+        ourmac,
+        net::Ipv4Addr::UNSPECIFIED,
+        &[0; 6],
+        addr,
+    )
+    .flatten();
+    sock.send_msg(
+        &probe,
+        &mut raw::ControlMessage::new(),
+        raw::MsgFlags::empty(),
+        Some(&link_addr(ifindex, libc::ETH_P_ARP as u16, [0xff; 6])),
+    )
+    .await?;
+
+    let mut buf = [0u8; 1500];
+    tokio::time::timeout(timeout, async {
+        loop {
+            let len = sock.recv(&mut buf, raw::MsgFlags::empty()).await?;
+            if is_arp_conflict(&buf[..len], ourmac, clientmac, addr) {
+                return Ok(true);
+            }
+        }
+    })
+    .await
+    .unwrap_or(Ok(false))
+}
+
+async fn probe_icmp(
+    addr: net::Ipv4Addr,
+    timeout: std::time::Duration,
+) -> Result<bool, std::io::Error> {
+    let sock =
+        raw::RawSocket::new_with_protocol(libc::AF_INET, libc::SOCK_RAW, libc::IPPROTO_ICMP)?;
+    let id: u16 = rand::thread_rng().gen();
+    let echo = packet::Fragment::new_icmp_echo(id, 1, packet::Tail::Payload(b"erbium")).flatten();
+    sock.send_msg(
+        &echo,
+        &mut raw::ControlMessage::new(),
+        raw::MsgFlags::empty(),
+        Some(&SockAddr::new_inet(InetAddr::from_std(
+            &net::SocketAddr::V4(net::SocketAddrV4::new(addr, 0)),
+        ))),
+    )
+    .await?;
+
+    let mut buf = [0u8; 1500];
+    tokio::time::timeout(timeout, async {
+        loop {
+            let len = sock.recv(&mut buf, raw::MsgFlags::empty()).await?;
+            if is_echo_reply(&buf[..len], id, addr) {
+                return Ok(true);
+            }
+        }
+    })
+    .await
+    .unwrap_or(Ok(false))
+}
+
+/// Returns true if something other than the client at `clientmac` answered for `addr` within
+/// `timeout`.
+pub async fn address_in_use(
+    method: Probe,
+    ifindex: u32,
+    ourmac: &[u8; 6],
+    clientmac: &[u8],
+    addr: net::Ipv4Addr,
+    timeout: std::time::Duration,
+) -> Result<bool, std::io::Error> {
+    match method {
+        Probe::None => Ok(false),
+        Probe::Arp => probe_arp(ifindex, ourmac, clientmac, addr, timeout).await,
+        Probe::Icmp => probe_icmp(addr, timeout).await,
+    }
+}
+
+#[test]
+fn test_arp_conflict() {
+    let ourmac = [0x00, 0x00, 0x5E, 0x00, 0x53, 0x01];
+    let theirmac = [0x00, 0x00, 0x5E, 0x00, 0x53, 0x02];
+    let clientmac = [0x00, 0x00, 0x5E, 0x00, 0x53, 0x03];
+    let addr: net::Ipv4Addr = "192.0.2.10".parse().unwrap();
+    let reply = packet::Fragment::new_arp(2, &theirmac, addr, &ourmac, addr).flatten();
+    assert!(is_arp_conflict(&reply, &ourmac, &clientmac, addr));
+    /* Something else answering for a different address is fine */
+    assert!(!is_arp_conflict(
+        &reply,
+        &ourmac,
+        &clientmac,
+        "192.0.2.11".parse().unwrap()
+    ));
+    /* As is our own probe */
+    let probe =
+        packet::Fragment::new_arp(1, &ourmac, net::Ipv4Addr::UNSPECIFIED, &[0; 6], addr).flatten();
+    assert!(!is_arp_conflict(&probe, &ourmac, &clientmac, addr));
+    assert!(!is_arp_conflict(&reply[..20], &ourmac, &clientmac, addr));
+    /* And the client we're offering it to already using it */
+    let reply = packet::Fragment::new_arp(2, &clientmac, addr, &ourmac, addr).flatten();
+    assert!(!is_arp_conflict(&reply, &ourmac, &clientmac, addr));
+}
+
+#[test]
+fn test_echo_reply() {
+    let addr: net::Ipv4Addr = "192.0.2.10".parse().unwrap();
+    let mut pkt = vec![0x45, 0, 0, 28, 0, 0, 0, 0, 64, 1, 0, 0];
+    pkt.extend_from_slice(&addr.octets());
+    pkt.extend_from_slice(&[192, 0, 2, 1]);
+    pkt.extend_from_slice(&[ICMP_ECHO_REPLY, 0, 0, 0, 0x12, 0x34, 0, 1]);
+    assert!(is_echo_reply(&pkt, 0x1234, addr));
+    assert!(!is_echo_reply(&pkt, 0x4321, addr));
+    assert!(!is_echo_reply(&pkt, 0x1234, "192.0.2.11".parse().unwrap()));
+    assert!(!is_echo_reply(&pkt[..24], 0x1234, addr));
+}
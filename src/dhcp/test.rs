@@ -68,6 +68,7 @@ fn mk_dhcp_request() -> dhcp::DHCPRequest {
         pkt: mk_dhcp_request_pkt(),
         serverip: SERVER_IP,
         ifindex: 1,
+        ifname: Some("eth0".into()),
     }
 }
 
@@ -86,7 +87,9 @@ fn mk_default_config() -> crate::config::Config {
                 apply_address: Some(apply_address),
                 ..Default::default()
             }],
+            ..Default::default()
         },
+        dns: Default::default(),
     }
 }
 
@@ -238,8 +241,69 @@ async fn confirm_yiaddr_set() {
 
 /* rfc2131 Section 3.2 Step 1: The server MUST broadcast the DHCPNAK message to the 0xffffffff broadcast address because the client may not have a correct network address or subnet mask, and the client may not be answering ARP requests.  Otherwise, the server MUST send the DHCPNAK message to the IP address of the BOOTP relay agent, as recorded in 'giaddr'.
  */
+#[test]
 fn broadcast_failed_renew() {
-    /* TODO */
+    /* A client that has moved networks tries to renew an address that isn't on this network */
+    let mut request = mk_dhcp_request();
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTID, &CLIENTID);
+    request.pkt.ciaddr = "198.51.100.1".parse().unwrap();
+
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let mut serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    serverids.insert(SERVER_IP);
+    let conf = mk_default_config();
+    let reply = dhcp::handle_pkt(&mut p, &request, serverids, &conf)
+        .expect("Failed to handle request")
+        .reply
+        .expect("No reply sent");
+    assert_eq!(reply.options.get_messagetype(), Some(dhcppkt::DHCPNAK));
+    assert_eq!(reply.yiaddr, net::Ipv4Addr::UNSPECIFIED);
+    assert_eq!(reply.ciaddr, net::Ipv4Addr::UNSPECIFIED);
+    assert!(reply
+        .options
+        .get_raw_option(&dhcppkt::OPTION_MESSAGE)
+        .is_some());
+    assert!(reply
+        .options
+        .get_option::<u32>(&dhcppkt::OPTION_LEASETIME)
+        .is_none());
+    assert!(dhcp::must_broadcast(&reply));
+}
+
+#[test]
+fn nak_init_reboot_for_address_in_use() {
+    /* A rebooting client asks for an address that another client currently holds */
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let mut serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    serverids.insert(SERVER_IP);
+    let conf = mk_single_address_config();
+
+    let offer = dhcp::handle_pkt(
+        &mut p,
+        &mk_discover(b"Other Client"),
+        serverids.clone(),
+        &conf,
+    )
+    .expect("Failed to handle discover")
+    .reply
+    .expect("No reply sent");
+    assert_eq!(offer.yiaddr, EXAMPLE_IP4);
+
+    let mut request = mk_dhcp_request();
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTID, &CLIENTID)
+        .set_option(&dhcppkt::OPTION_ADDRESSREQUEST, &EXAMPLE_IP4);
+    let reply = dhcp::handle_pkt(&mut p, &request, serverids, &conf)
+        .expect("Failed to handle request")
+        .reply
+        .expect("No reply sent");
+    assert_eq!(reply.options.get_messagetype(), Some(dhcppkt::DHCPNAK));
+    assert_eq!(reply.yiaddr, net::Ipv4Addr::UNSPECIFIED);
 }
 
 /* rfc2131 Section 3.2 Step 3: If the client detects that the IP address in the DHCPACK message is
@@ -252,8 +316,70 @@ fn broadcast_failed_renew() {
 /* rfc2131 Section 3.4: The server SHOULD check the network address in a DHCPINFORM message for
  * consistency, but MUST NOT check for an existing lease.
  */
+#[test]
 fn dhcpinform_dont_check_existing_lease() {
-    /* TODO */
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let mut serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    serverids.insert(SERVER_IP);
+    let mut conf = mk_default_config();
+    conf.dhcp.policies[0].apply_other.insert(
+        dhcppkt::OPTION_DOMAINSERVER,
+        dhcppkt::DhcpOptionTypeValue::IpList(vec![EXAMPLE_IP4]),
+    );
+    conf.dhcp.policies[0].apply_other.insert(
+        dhcppkt::OPTION_RENEWALTIME,
+        dhcppkt::DhcpOptionTypeValue::U32(600),
+    );
+
+    let mut request = mk_dhcp_request();
+    request.pkt.ciaddr = EXAMPLE_IP2;
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTID, &CLIENTID)
+        .set_option(
+            &dhcppkt::OPTION_PARAMLIST,
+            &vec![dhcppkt::OPTION_DOMAINSERVER],
+        )
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPINFORM);
+
+    let reply = dhcp::handle_pkt(&mut p, &request, serverids.clone(), &conf)
+        .expect("Failed to handle inform")
+        .reply
+        .expect("No reply sent");
+    assert_eq!(reply.options.get_messagetype(), Some(dhcppkt::DHCPACK));
+    assert_eq!(reply.ciaddr, EXAMPLE_IP2);
+    assert_eq!(reply.yiaddr, net::Ipv4Addr::UNSPECIFIED);
+    assert!(reply
+        .options
+        .get_option::<Vec<u8>>(&dhcppkt::OPTION_LEASETIME)
+        .is_none());
+    assert!(reply
+        .options
+        .get_option::<Vec<u8>>(&dhcppkt::OPTION_RENEWALTIME)
+        .is_none());
+    assert_eq!(
+        reply
+            .options
+            .get_option::<Vec<net::Ipv4Addr>>(&dhcppkt::OPTION_DOMAINSERVER),
+        Some(vec![EXAMPLE_IP4])
+    );
+
+    /* The informing client's address shouldn't have been recorded in the pool, so a different
+     * client is still able to renew it.
+     */
+    let mut request = mk_dhcp_request();
+    request.pkt.ciaddr = EXAMPLE_IP2;
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTID, &b"Other Client".to_vec())
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPREQUEST);
+    let reply = dhcp::handle_pkt(&mut p, &request, serverids, &conf)
+        .expect("Failed to handle request")
+        .reply
+        .expect("No reply sent");
+    assert_eq!(reply.yiaddr, EXAMPLE_IP2);
 }
 
 /* rfc2131 Section 3.5: If the client includes a list of parameters in a DHCPDISCOVER message, it
@@ -461,8 +587,10 @@ fn offer_required() {
     let mut serverids: dhcp::ServerIds = dhcp::ServerIds::new();
     serverids.insert(SERVER_IP);
     let conf = mk_default_config();
-    let reply =
-        dhcp::handle_pkt(&mut p, &request, serverids, &conf).expect("Failed to handle request");
+    let reply = dhcp::handle_pkt(&mut p, &request, serverids, &conf)
+        .expect("Failed to handle request")
+        .reply
+        .expect("No reply sent");
     assert_eq!(reply.op, dhcppkt::OP_BOOTREPLY);
     assert_eq!(reply.htype, dhcppkt::HWTYPE_ETHERNET);
     assert_eq!(reply.hlen, 6);
@@ -513,8 +641,10 @@ fn ack_required() {
     let mut serverids: dhcp::ServerIds = dhcp::ServerIds::new();
     serverids.insert(SERVER_IP);
     let conf = mk_default_config();
-    let reply =
-        dhcp::handle_pkt(&mut p, &request, serverids, &conf).expect("Failed to handle request");
+    let reply = dhcp::handle_pkt(&mut p, &request, serverids, &conf)
+        .expect("Failed to handle request")
+        .reply
+        .expect("No reply sent");
     assert_eq!(reply.op, dhcppkt::OP_BOOTREPLY);
     assert_eq!(reply.htype, dhcppkt::HWTYPE_ETHERNET);
     assert_eq!(reply.hlen, 6);
@@ -569,8 +699,10 @@ fn test_renew_unknown() {
     let mut serverids: dhcp::ServerIds = dhcp::ServerIds::new();
     serverids.insert(SERVER_IP);
     let conf = mk_default_config();
-    let reply =
-        dhcp::handle_pkt(&mut p, &request, serverids, &conf).expect("Failed to handle request");
+    let reply = dhcp::handle_pkt(&mut p, &request, serverids, &conf)
+        .expect("Failed to handle request")
+        .reply
+        .expect("No reply sent");
     assert_eq!(reply.yiaddr, EXAMPLE_IP2);
 }
 
@@ -596,7 +728,9 @@ fn test_full() {
         .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPDISCOVER);
 
     let offer = dhcp::handle_pkt(&mut p, &request, serverids.clone(), &conf)
-        .expect("Failed to handle request");
+        .expect("Failed to handle request")
+        .reply
+        .expect("No reply sent");
 
     serverids.insert(offer.options.get_serverid().unwrap());
 
@@ -614,7 +748,9 @@ fn test_full() {
         .set_option(&dhcppkt::OPTION_ADDRESSREQUEST, &offer.yiaddr);
 
     let ack = dhcp::handle_pkt(&mut p, &request, serverids.clone(), &conf)
-        .expect("Failed to handle request");
+        .expect("Failed to handle request")
+        .reply
+        .expect("No reply sent");
 
     assert_eq!(ack.options.get_messagetype(), Some(dhcppkt::DHCPACK));
     assert_eq!(ack.yiaddr, offer.yiaddr); /* make sure we don't needlessly change our mind */
@@ -631,7 +767,9 @@ fn test_full() {
         .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPREQUEST);
     /* no server id */
     let ack = dhcp::handle_pkt(&mut p, &request, serverids.clone(), &conf)
-        .expect("Failed to handle request");
+        .expect("Failed to handle request")
+        .reply
+        .expect("No reply sent");
     assert_eq!(ack.options.get_messagetype(), Some(dhcppkt::DHCPACK));
     assert_eq!(ack.yiaddr, offer.yiaddr); /* Did we get back the same address? */
 
@@ -639,15 +777,225 @@ fn test_full() {
     let mut request = mk_dhcp_request();
     /* xid and seconds are not copied from the previous requests */
     request.pkt.secs = 0;
+    request.pkt.ciaddr = offer.yiaddr;
     request.pkt.options = request
         .pkt
         .options
         .set_option(&dhcppkt::OPTION_CLIENTID, &CLIENTID)
+        .set_option(&dhcppkt::OPTION_SERVERID, &SERVER_IP)
         .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPRELEASE);
-    /* no server id */
-    /* release is not supported, so we expect an error here.  But we shouldn't crash */
-    let _ack =
-        dhcp::handle_pkt(&mut p, &request, serverids, &conf).expect_err("Failed to handle request");
+    /* There is no reply to a DHCPRELEASE, just the address that was released */
+    assert_eq!(
+        dhcp::handle_pkt(&mut p, &request, serverids, &conf).expect("Failed to handle release"),
+        dhcp::Handled {
+            reply: None,
+            released: Some(offer.yiaddr),
+        }
+    );
+}
+
+fn mk_single_address_config() -> crate::config::Config {
+    let mut apply_address: pool::PoolAddresses = Default::default();
+    apply_address.insert(EXAMPLE_IP4);
+    crate::config::Config {
+        dhcp: dhcp::config::Config {
+            policies: vec![dhcp::config::Policy {
+                match_subnet: Some(
+                    crate::net::Ipv4Subnet::new("192.0.2.0".parse().unwrap(), 24).unwrap(),
+                ),
+                apply_address: Some(apply_address),
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        dns: Default::default(),
+    }
+}
+
+fn mk_release(clientid: &[u8], addr: net::Ipv4Addr, serverid: net::Ipv4Addr) -> dhcp::DHCPRequest {
+    let mut request = mk_dhcp_request();
+    request.pkt.ciaddr = addr;
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTID, &clientid)
+        .set_option(&dhcppkt::OPTION_SERVERID, &serverid)
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPRELEASE);
+    request
+}
+
+fn mk_discover(clientid: &[u8]) -> dhcp::DHCPRequest {
+    let mut request = mk_dhcp_request();
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTID, &clientid)
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPDISCOVER);
+    request
+}
+
+/* RFC2131 Section 4.4.6: Upon receipt of a DHCPRELEASE message, the server marks the network
+ * address as not allocated.
+ */
+#[test]
+fn release_then_rediscover() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let mut serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    serverids.insert(SERVER_IP);
+    let conf = mk_single_address_config();
+
+    let offer = dhcp::handle_pkt(&mut p, &mk_discover(CLIENTID), serverids.clone(), &conf)
+        .expect("Failed to handle discover")
+        .reply
+        .expect("No reply sent");
+    assert_eq!(offer.yiaddr, EXAMPLE_IP4);
+
+    /* The only address is in use, so another client can't have it */
+    assert_eq!(
+        dhcp::handle_pkt(
+            &mut p,
+            &mk_discover(b"Other Client"),
+            serverids.clone(),
+            &conf
+        ),
+        Err(dhcp::DhcpError::NoLeasesAvailable)
+    );
+
+    assert_eq!(
+        dhcp::handle_pkt(
+            &mut p,
+            &mk_release(CLIENTID, EXAMPLE_IP4, SERVER_IP),
+            serverids.clone(),
+            &conf
+        )
+        .expect("Failed to handle release"),
+        dhcp::Handled {
+            reply: None,
+            released: Some(EXAMPLE_IP4),
+        }
+    );
+
+    /* Now that it's been released, the other client should be able to get it */
+    let offer = dhcp::handle_pkt(&mut p, &mk_discover(b"Other Client"), serverids, &conf)
+        .expect("Failed to handle discover")
+        .reply
+        .expect("No reply sent");
+    assert_eq!(offer.yiaddr, EXAMPLE_IP4);
+}
+
+/* The lease script is only told about releases of leases that existed */
+#[test]
+fn release_reports_released_lease() {
+    /* The lease script is only run for leases that were actually released */
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let mut serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    serverids.insert(SERVER_IP);
+    let conf = mk_single_address_config();
+
+    dhcp::handle_pkt(&mut p, &mk_discover(CLIENTID), serverids.clone(), &conf)
+        .expect("Failed to handle discover")
+        .reply
+        .expect("No reply sent");
+    let released: Vec<_> = [&b"Other Client"[..], CLIENTID, CLIENTID]
+        .iter()
+        .map(|clientid| {
+            dhcp::handle_pkt(
+                &mut p,
+                &mk_release(clientid, EXAMPLE_IP4, SERVER_IP),
+                serverids.clone(),
+                &conf,
+            )
+            .expect("Failed to handle release")
+            .released
+        })
+        .collect();
+    assert_eq!(released, vec![None, Some(EXAMPLE_IP4), None]);
+}
+
+#[test]
+fn release_from_other_client_ignored() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let mut serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    serverids.insert(SERVER_IP);
+    let conf = mk_single_address_config();
+
+    dhcp::handle_pkt(&mut p, &mk_discover(CLIENTID), serverids.clone(), &conf)
+        .expect("Failed to handle discover")
+        .reply
+        .expect("No reply sent");
+
+    /* A different client shouldn't be able to release someone else's lease */
+    dhcp::handle_pkt(
+        &mut p,
+        &mk_release(b"Other Client", EXAMPLE_IP4, SERVER_IP),
+        serverids.clone(),
+        &conf,
+    )
+    .expect("Failed to handle release");
+
+    assert_eq!(
+        dhcp::handle_pkt(&mut p, &mk_discover(b"Other Client"), serverids, &conf),
+        Err(dhcp::DhcpError::NoLeasesAvailable)
+    );
+}
+
+#[test]
+fn release_for_other_server() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let mut serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    serverids.insert(SERVER_IP);
+    let conf = mk_single_address_config();
+
+    assert_eq!(
+        dhcp::handle_pkt(
+            &mut p,
+            &mk_release(CLIENTID, EXAMPLE_IP4, NOT_SERVER_IP),
+            serverids,
+            &conf
+        ),
+        Err(dhcp::DhcpError::OtherServer)
+    );
+}
+
+/* RFC2131 Section 4.3.3: If the server receives a DHCPDECLINE message, the client has discovered
+ * through some other means that the suggested network address is already in use.  The server MUST
+ * mark the network address as not available and SHOULD notify the local system administrator of a
+ * possible configuration problem.
+ */
+#[test]
+fn decline_quarantines_address() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let mut serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    serverids.insert(SERVER_IP);
+    let conf = mk_single_address_config();
+
+    let offer = dhcp::handle_pkt(&mut p, &mk_discover(CLIENTID), serverids.clone(), &conf)
+        .expect("Failed to handle discover")
+        .reply
+        .expect("No reply sent");
+    assert_eq!(offer.yiaddr, EXAMPLE_IP4);
+
+    let mut decline = mk_dhcp_request();
+    decline.pkt.options = decline
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTID, &CLIENTID)
+        .set_option(&dhcppkt::OPTION_SERVERID, &SERVER_IP)
+        .set_option(&dhcppkt::OPTION_ADDRESSREQUEST, &offer.yiaddr)
+        .set_option(&dhcppkt::OPTION_MSGTYPE, &dhcppkt::DHCPDECLINE);
+    assert_eq!(
+        dhcp::handle_pkt(&mut p, &decline, serverids.clone(), &conf)
+            .expect("Failed to handle decline"),
+        Default::default()
+    );
+
+    /* The declined address should not be handed out again, to this client or any other */
+    for client in &[CLIENTID, b"Other Client"] {
+        assert_eq!(
+            dhcp::handle_pkt(&mut p, &mk_discover(client), serverids.clone(), &conf),
+            Err(dhcp::DhcpError::NoLeasesAvailable)
+        );
+    }
 }
 
 /* TODO:
@@ -655,3 +1003,764 @@ fn test_full() {
  *    the DHCPREQUEST message use the message as notification that the client has declined that
  *    server's offer.
 */
+
+const RELAY_IP: net::Ipv4Addr = net::Ipv4Addr::new(198, 51, 100, 1); /* Documentation prefix 2 */
+const RELAY_IP2: net::Ipv4Addr = net::Ipv4Addr::new(203, 0, 113, 1); /* Documentation prefix 3 */
+
+/* A config with one pool on the local network, and one pool on each of two relayed networks */
+fn mk_relay_config() -> crate::config::Config {
+    let mk_policy = |net: &str, host: u32| {
+        let subnet = crate::net::Ipv4Subnet::new(net.parse().unwrap(), 24).unwrap();
+        let mut apply_address: pool::PoolAddresses = Default::default();
+        apply_address.insert((u32::from(subnet.addr) + host).into());
+        dhcp::config::Policy {
+            match_subnet: Some(subnet),
+            apply_address: Some(apply_address),
+            ..Default::default()
+        }
+    };
+    crate::config::Config {
+        dhcp: dhcp::config::Config {
+            policies: vec![
+                mk_policy("192.0.2.0", 10),
+                mk_policy("198.51.100.0", 10),
+                mk_policy("203.0.113.0", 10),
+            ],
+            ..Default::default()
+        },
+        dns: Default::default(),
+    }
+}
+
+#[test]
+fn relayed_discover_uses_relay_subnet() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let mut serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    serverids.insert(SERVER_IP);
+    let conf = mk_relay_config();
+
+    let mut request = mk_discover(CLIENTID);
+    request.pkt.giaddr = RELAY_IP;
+    let reply = dhcp::handle_pkt(&mut p, &request, serverids, &conf)
+        .expect("Failed to handle request")
+        .reply
+        .expect("No reply sent");
+    assert_eq!(
+        reply.yiaddr,
+        "198.51.100.10".parse::<net::Ipv4Addr>().unwrap()
+    );
+    /* The relay needs giaddr preserved to know where to forward the reply */
+    assert_eq!(reply.giaddr, RELAY_IP);
+    assert_eq!(reply.options.get_serverid(), Some(SERVER_IP));
+}
+
+#[test]
+fn relayed_link_selection() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let mut serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    serverids.insert(SERVER_IP);
+    let conf = mk_relay_config();
+
+    /* RFC3527: The link selection sub-option overrides giaddr */
+    let mut request = mk_discover(CLIENTID);
+    request.pkt.giaddr = RELAY_IP;
+    request.pkt.options = request.pkt.options.set_raw_option(
+        &dhcppkt::OPTION_RELAYINFO,
+        &[dhcppkt::RELAYINFO_LINKSELECTION, 4, 203, 0, 113, 0],
+    );
+    let reply = dhcp::handle_pkt(&mut p, &request, serverids.clone(), &conf)
+        .expect("Failed to handle request")
+        .reply
+        .expect("No reply sent");
+    assert_eq!(
+        reply.yiaddr,
+        "203.0.113.10".parse::<net::Ipv4Addr>().unwrap()
+    );
+
+    /* RFC3011: The subnet selection option overrides both */
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_SUBNETSELECT, &SERVER_IP);
+    let reply = dhcp::handle_pkt(&mut p, &request, serverids, &conf)
+        .expect("Failed to handle request")
+        .reply
+        .expect("No reply sent");
+    assert_eq!(reply.yiaddr, "192.0.2.10".parse::<net::Ipv4Addr>().unwrap());
+}
+
+#[test]
+fn relayed_nak_not_broadcast() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let mut serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    serverids.insert(SERVER_IP);
+    let conf = mk_relay_config();
+
+    /* The client has moved from behind one relay to another */
+    let mut request = mk_dhcp_request();
+    request.pkt.giaddr = RELAY_IP2;
+    request.pkt.ciaddr = "198.51.100.10".parse().unwrap();
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTID, &CLIENTID);
+    let reply = dhcp::handle_pkt(&mut p, &request, serverids, &conf)
+        .expect("Failed to handle request")
+        .reply
+        .expect("No reply sent");
+    assert_eq!(reply.options.get_messagetype(), Some(dhcppkt::DHCPNAK));
+    assert_eq!(reply.giaddr, RELAY_IP2);
+    /* It's sent to the relay, which is asked to broadcast it on our behalf */
+    assert!(!dhcp::must_broadcast(&reply));
+    assert_eq!(
+        reply.flags & dhcppkt::FLAG_BROADCAST,
+        dhcppkt::FLAG_BROADCAST
+    );
+}
+
+fn mk_relay_info(circuit_id: &[u8], remote_id: &[u8]) -> Vec<u8> {
+    let mut v = vec![dhcppkt::RELAYINFO_CIRCUITID, circuit_id.len() as u8];
+    v.extend_from_slice(circuit_id);
+    v.extend_from_slice(&[dhcppkt::RELAYINFO_REMOTEID, remote_id.len() as u8]);
+    v.extend_from_slice(remote_id);
+    v
+}
+
+#[test]
+fn parse_relay_info() {
+    let mut raw = mk_relay_info(b"eth0/1", b"switch1");
+    raw.extend_from_slice(&[dhcppkt::RELAYINFO_SUBSCRIBERID, 3, b'a', b'b', b'c']);
+    raw.extend_from_slice(&[99, 1, 0]); /* Unknown sub-options are skipped */
+    let options = dhcppkt::DhcpOptions {
+        ..Default::default()
+    }
+    .set_raw_option(&dhcppkt::OPTION_RELAYINFO, &raw);
+    assert_eq!(
+        options.get_relay_info(),
+        Some(dhcppkt::RelayAgentInformation {
+            circuit_id: Some(b"eth0/1".to_vec()),
+            remote_id: Some(b"switch1".to_vec()),
+            link_selection: None,
+            subscriber_id: Some(b"abc".to_vec()),
+        })
+    );
+
+    /* A truncated sub-option is invalid */
+    let options = options.set_raw_option(&dhcppkt::OPTION_RELAYINFO, &[1, 10, 0]);
+    assert_eq!(options.get_relay_info(), None);
+}
+
+#[test]
+fn match_relay_circuit_id() {
+    let mk_policy = |circuit_id: &[u8], addr| {
+        let mut apply_address: pool::PoolAddresses = Default::default();
+        apply_address.insert(addr);
+        dhcp::config::Policy {
+            match_circuit_id: Some(circuit_id.to_vec()),
+            match_remote_id: Some(b"switch1".to_vec()),
+            apply_address: Some(apply_address),
+            ..Default::default()
+        }
+    };
+    let conf = crate::config::Config {
+        dhcp: dhcp::config::Config {
+            policies: vec![dhcp::config::Policy {
+                match_subnet: Some(
+                    crate::net::Ipv4Subnet::new("198.51.100.0".parse().unwrap(), 24).unwrap(),
+                ),
+                policies: vec![
+                    mk_policy(b"eth0/1", "198.51.100.1".parse().unwrap()),
+                    mk_policy(b"eth0/2", "198.51.100.2".parse().unwrap()),
+                ],
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        dns: Default::default(),
+    };
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let mut serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    serverids.insert(SERVER_IP);
+
+    let relay_info = mk_relay_info(b"eth0/2", b"switch1");
+    let mut request = mk_discover(CLIENTID);
+    request.pkt.giaddr = RELAY_IP;
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_raw_option(&dhcppkt::OPTION_RELAYINFO, &relay_info);
+    let reply = dhcp::handle_pkt(&mut p, &request, serverids.clone(), &conf)
+        .expect("Failed to handle request")
+        .reply
+        .expect("No reply sent");
+    assert_eq!(
+        reply.yiaddr,
+        "198.51.100.2".parse::<net::Ipv4Addr>().unwrap()
+    );
+
+    /* RFC3046 Section 2.2: The option is echoed back unchanged, as the last option */
+    assert_eq!(
+        reply.options.get_raw_option(&dhcppkt::OPTION_RELAYINFO),
+        Some(relay_info.as_slice())
+    );
+    let bytes = reply.serialise();
+    let mut last_option = vec![82, relay_info.len() as u8];
+    last_option.extend_from_slice(&relay_info);
+    last_option.push(255);
+    assert!(bytes.ends_with(&last_option));
+
+    /* A port on a different switch doesn't match */
+    request.pkt.options = request.pkt.options.set_raw_option(
+        &dhcppkt::OPTION_RELAYINFO,
+        &mk_relay_info(b"eth0/2", b"switch2"),
+    );
+    assert_eq!(
+        dhcp::handle_pkt(&mut p, &request, serverids, &conf),
+        Err(dhcp::DhcpError::NoLeasesAvailable)
+    );
+}
+
+#[test]
+fn match_interface_policy() {
+    let mk_policy = |interface: &str, addr| {
+        let mut apply_address: pool::PoolAddresses = Default::default();
+        apply_address.insert(addr);
+        dhcp::config::Policy {
+            match_interface: Some(interface.into()),
+            apply_address: Some(apply_address),
+            ..Default::default()
+        }
+    };
+    let conf = crate::config::Config {
+        dhcp: dhcp::config::Config {
+            policies: vec![
+                mk_policy("eth0", EXAMPLE_IP2),
+                mk_policy("vlan*", EXAMPLE_IP3),
+            ],
+            ..Default::default()
+        },
+        dns: Default::default(),
+    };
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let mut serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    serverids.insert(SERVER_IP);
+
+    let mut request = mk_discover(CLIENTID);
+    request.ifname = Some("vlan10".into());
+    let reply = dhcp::handle_pkt(&mut p, &request, serverids.clone(), &conf)
+        .expect("Failed to handle request")
+        .reply
+        .expect("No reply sent");
+    assert_eq!(reply.yiaddr, EXAMPLE_IP3);
+
+    let mut request = mk_discover(b"Other Client");
+    request.ifname = Some("eth0".into());
+    let reply = dhcp::handle_pkt(&mut p, &request, serverids.clone(), &conf)
+        .expect("Failed to handle request")
+        .reply
+        .expect("No reply sent");
+    assert_eq!(reply.yiaddr, EXAMPLE_IP2);
+
+    /* Interfaces that don't match (or that we can't name) don't get an address */
+    for ifname in &[Some("eth1".to_string()), None] {
+        let mut request = mk_discover(b"Third Client");
+        request.ifname = ifname.clone();
+        assert_eq!(
+            dhcp::handle_pkt(&mut p, &request, serverids.clone(), &conf),
+            Err(dhcp::DhcpError::NoPolicyConfigured)
+        );
+    }
+}
+
+#[tokio::test]
+async fn policy_lease_times() {
+    let conf = crate::config::load_config_from_string_for_test(
+        "---
+dhcp:
+    policies:
+      - match-subnet: 192.0.2.0/24
+        apply-range: {start: 192.0.2.10, end: 192.0.2.20}
+        apply-default-lease: 1h
+        apply-max-lease: 2h
+",
+    )
+    .expect("Failed to parse config");
+    let conf = conf.lock().await;
+    assert_eq!(
+        conf.dhcp.policies[0].apply_default_lease,
+        Some(std::time::Duration::from_secs(3600))
+    );
+    assert_eq!(
+        conf.dhcp.policies[0].apply_max_lease,
+        Some(std::time::Duration::from_secs(7200))
+    );
+
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let mut serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    serverids.insert(SERVER_IP);
+
+    let offer = dhcp::handle_pkt(&mut p, &mk_discover(CLIENTID), serverids.clone(), &conf)
+        .expect("Failed to handle request")
+        .reply
+        .expect("No reply sent");
+    assert_eq!(
+        offer.options.get_option::<u32>(&dhcppkt::OPTION_LEASETIME),
+        Some(3600)
+    );
+    /* RFC2131 Section 4.4.5: T1 and T2 default to 0.5 and 0.875 of the lease time */
+    assert_eq!(
+        offer
+            .options
+            .get_option::<u32>(&dhcppkt::OPTION_RENEWALTIME),
+        Some(1800)
+    );
+    assert_eq!(
+        offer.options.get_option::<u32>(&dhcppkt::OPTION_REBINDTIME),
+        Some(3150)
+    );
+
+    let mut request = mk_dhcp_request();
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTID, &CLIENTID)
+        .set_option(&dhcppkt::OPTION_SERVERID, &SERVER_IP)
+        .set_option(&dhcppkt::OPTION_ADDRESSREQUEST, &offer.yiaddr);
+    let ack = dhcp::handle_pkt(&mut p, &request, serverids, &conf)
+        .expect("Failed to handle request")
+        .reply
+        .expect("No reply sent");
+    assert_eq!(ack.options.get_messagetype(), Some(dhcppkt::DHCPACK));
+    let leasetime = ack
+        .options
+        .get_option::<u32>(&dhcppkt::OPTION_LEASETIME)
+        .expect("No lease time in ACK");
+    assert!(
+        (3600..=7200).contains(&leasetime),
+        "lease time {} out of range",
+        leasetime
+    );
+}
+
+/* Policies' T1 and T2 are sent with the lease, even to clients that don't ask for them */
+#[tokio::test]
+async fn policy_renewal_times_sent_unrequested() {
+    let conf = crate::config::load_config_from_string_for_test(
+        "---
+dhcp:
+    policies:
+      - match-subnet: 192.0.2.0/24
+        apply-range: {start: 192.0.2.10, end: 192.0.2.20}
+        apply-default-lease: 1h
+        apply-renewal-time: 10m
+        apply-rebind-time: 20m
+",
+    )
+    .expect("Failed to parse config");
+    let conf = conf.lock().await;
+
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let mut serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    serverids.insert(SERVER_IP);
+
+    let offer = dhcp::handle_pkt(&mut p, &mk_discover(CLIENTID), serverids.clone(), &conf)
+        .expect("Failed to handle request")
+        .reply
+        .expect("No reply sent");
+    let mut request = mk_dhcp_request();
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTID, &CLIENTID)
+        .set_option(&dhcppkt::OPTION_SERVERID, &SERVER_IP)
+        .set_option(&dhcppkt::OPTION_ADDRESSREQUEST, &offer.yiaddr);
+    assert!(request
+        .pkt
+        .options
+        .get_raw_option(&dhcppkt::OPTION_PARAMLIST)
+        .is_none());
+    let ack = dhcp::handle_pkt(&mut p, &request, serverids, &conf)
+        .expect("Failed to handle request")
+        .reply
+        .expect("No reply sent");
+    assert_eq!(ack.options.get_messagetype(), Some(dhcppkt::DHCPACK));
+    for reply in &[offer, ack] {
+        assert_eq!(
+            reply
+                .options
+                .get_option::<u32>(&dhcppkt::OPTION_RENEWALTIME),
+            Some(600)
+        );
+        assert_eq!(
+            reply.options.get_option::<u32>(&dhcppkt::OPTION_REBINDTIME),
+            Some(1200)
+        );
+    }
+}
+
+#[test]
+fn lease_records_client_details() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let mut serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    serverids.insert(SERVER_IP);
+    let conf = mk_relay_config();
+
+    let mut request = mk_discover(CLIENTID);
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_HOSTNAME, &"myhost".to_string())
+        .set_option(&dhcppkt::OPTION_VENDOR_CLASS, &"erbium-test".to_string());
+    request.pkt.giaddr = RELAY_IP;
+    dhcp::handle_pkt(&mut p, &request, serverids, &conf)
+        .expect("Failed to handle request")
+        .reply
+        .expect("No reply sent");
+
+    let leases = p
+        .get_leases(&Default::default())
+        .expect("Failed to get leases");
+    assert_eq!(leases.len(), 1);
+    assert_eq!(leases[0].clientid, Some(CLIENTID.to_vec()));
+    assert_eq!(
+        leases[0].client,
+        pool::ClientInfo {
+            chaddr: Some(request.pkt.chaddr.clone()),
+            hostname: Some("myhost".into()),
+            vendor_class: Some("erbium-test".into()),
+            /* The second policy in mk_relay_config() */
+            policy: Some("1".into()),
+        }
+    );
+}
+
+#[test]
+fn lease_database_write_failure() {
+    /* If we can't record the lease, we mustn't hand out the address */
+    let mut p = pool::Pool::new_read_only_for_test().expect("Failed to create pool");
+    let mut serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    serverids.insert(SERVER_IP);
+    let conf = mk_default_config();
+
+    match dhcp::handle_pkt(&mut p, &mk_discover(CLIENTID), serverids, &conf) {
+        Err(dhcp::DhcpError::InternalError(_)) => (),
+        x => panic!("Unexpected result {:?}", x),
+    }
+}
+
+fn mk_reservation_config() -> crate::config::Config {
+    let mut conf = mk_default_config();
+    conf.dhcp.reservations = vec![
+        dhcp::config::Reservation {
+            key: dhcp::config::ReservationKey::ClientId(CLIENTID.to_vec()),
+            address: EXAMPLE_IP4,
+        },
+        dhcp::config::Reservation {
+            key: dhcp::config::ReservationKey::HostName("printer".into()),
+            address: EXAMPLE_IP3,
+        },
+    ];
+    conf
+}
+
+#[test]
+fn reserved_address_only_for_owner() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let mut serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    serverids.insert(SERVER_IP);
+    let conf = mk_reservation_config();
+
+    /* Another client asking for a reserved address doesn't get it */
+    let mut request = mk_discover(b"Other Client");
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_ADDRESSREQUEST, &EXAMPLE_IP4);
+    let offer = dhcp::handle_pkt(&mut p, &request, serverids.clone(), &conf)
+        .expect("Failed to handle discover")
+        .reply
+        .expect("No reply sent");
+    assert_ne!(offer.yiaddr, EXAMPLE_IP4);
+    assert_ne!(offer.yiaddr, EXAMPLE_IP3);
+
+    /* Nor can it request one directly */
+    let mut request = mk_dhcp_request();
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTID, &&b"Other Client"[..])
+        .set_option(&dhcppkt::OPTION_ADDRESSREQUEST, &EXAMPLE_IP4);
+    let reply = dhcp::handle_pkt(&mut p, &request, serverids.clone(), &conf)
+        .expect("Failed to handle request")
+        .reply
+        .expect("No reply sent");
+    assert_eq!(
+        reply.options.get_messagetype(),
+        Some(dhcppkt::DHCPNAK),
+        "{:?}",
+        reply
+    );
+
+    /* The client it's reserved for gets it, even when it asks for something else */
+    let mut request = mk_discover(CLIENTID);
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_ADDRESSREQUEST, &EXAMPLE_IP2);
+    let offer = dhcp::handle_pkt(&mut p, &request, serverids, &conf)
+        .expect("Failed to handle discover")
+        .reply
+        .expect("No reply sent");
+    assert_eq!(offer.yiaddr, EXAMPLE_IP4);
+}
+
+#[test]
+fn reservation_by_host_name() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let mut serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    serverids.insert(SERVER_IP);
+    let conf = mk_reservation_config();
+
+    let mut request = mk_discover(b"Printer");
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_HOSTNAME, &"printer".to_string());
+    let offer = dhcp::handle_pkt(&mut p, &request, serverids, &conf)
+        .expect("Failed to handle discover")
+        .reply
+        .expect("No reply sent");
+    assert_eq!(offer.yiaddr, EXAMPLE_IP3);
+}
+
+/* A client with a reservation that turns up somewhere its reserved address can't be used gets an
+ * address that works there instead.
+ */
+#[test]
+fn reservation_on_other_interface() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let mut serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    serverids.insert(SERVER_IP);
+    let other_address: net::Ipv4Addr = "198.51.100.10".parse().unwrap();
+    let mut conf = mk_reservation_config();
+    conf.dhcp.policies.insert(
+        0,
+        dhcp::config::Policy {
+            match_interface: Some("eth1".into()),
+            apply_address: Some(std::iter::once(other_address).collect()),
+            ..Default::default()
+        },
+    );
+
+    let mut request = mk_discover(CLIENTID);
+    request.ifname = Some("eth1".into());
+    let offer = dhcp::handle_pkt(&mut p, &request, serverids.clone(), &conf)
+        .expect("Failed to handle discover")
+        .reply
+        .expect("No reply sent");
+    assert_eq!(offer.yiaddr, other_address);
+
+    /* But it still gets its reserved address where it can use it */
+    let offer = dhcp::handle_pkt(&mut p, &mk_discover(CLIENTID), serverids, &conf)
+        .expect("Failed to handle discover")
+        .reply
+        .expect("No reply sent");
+    assert_eq!(offer.yiaddr, EXAMPLE_IP4);
+}
+
+#[tokio::test]
+async fn probe_settings() {
+    let conf = crate::config::load_config_from_string_for_test(
+        "---
+dhcp:
+    policies:
+      - match-subnet: 192.0.2.0/24
+        apply-range: {start: 192.0.2.10, end: 192.0.2.20}
+        apply-probe: arp
+        apply-probe-timeout: 250ms
+        policies:
+          - { match-hardware-address: 00:00:5E:00:53:01, apply-probe: none }
+      - match-subnet: 198.51.100.0/24
+        apply-range: {start: 198.51.100.10, end: 198.51.100.20}
+",
+    )
+    .expect("Failed to parse config");
+    let conf = conf.lock().await;
+
+    let request = mk_discover(CLIENTID);
+    assert_eq!(
+        dhcp::get_probe(&request, &conf),
+        (
+            dhcp::config::Probe::Arp,
+            std::time::Duration::from_millis(250)
+        )
+    );
+
+    /* Subpolicies can turn probing off again */
+    let mut request = mk_discover(CLIENTID);
+    request.pkt.chaddr = vec![0x00, 0x00, 0x5E, 0x00, 0x53, 0x01];
+    assert_eq!(
+        dhcp::get_probe(&request, &conf).0,
+        dhcp::config::Probe::None
+    );
+
+    /* We can't ARP through a relay, so ping instead */
+    let mut request = mk_discover(CLIENTID);
+    request.serverip = RELAY_IP;
+    request.pkt.giaddr = EXAMPLE_IP4;
+    assert_eq!(
+        dhcp::get_probe(&request, &conf).0,
+        dhcp::config::Probe::Icmp
+    );
+
+    /* Probing is off by default */
+    let mut request = mk_discover(CLIENTID);
+    request.pkt.giaddr = RELAY_IP;
+    assert_eq!(
+        dhcp::get_probe(&request, &conf),
+        (
+            dhcp::config::Probe::None,
+            dhcp::probe::DEFAULT_PROBE_TIMEOUT
+        )
+    );
+}
+
+fn mk_request(clientid: &[u8], addr: net::Ipv4Addr) -> dhcp::DHCPRequest {
+    let mut request = mk_dhcp_request();
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_option(&dhcppkt::OPTION_CLIENTID, &clientid)
+        .set_option(&dhcppkt::OPTION_ADDRESSREQUEST, &addr);
+    request
+}
+
+#[test]
+fn forcerenew_nonce() {
+    let mut p = pool::Pool::new_in_memory().expect("Failed to create pool");
+    let mut serverids: dhcp::ServerIds = dhcp::ServerIds::new();
+    serverids.insert(SERVER_IP);
+    let conf = mk_relay_config();
+
+    /* A client that doesn't support RFC6704 isn't given a nonce, and so can't be sent a
+     * DHCPFORCERENEW.
+     */
+    let ack = dhcp::handle_pkt(
+        &mut p,
+        &mk_request(b"Other Client", EXAMPLE_IP1),
+        serverids.clone(),
+        &mk_default_config(),
+    )
+    .expect("Failed to handle request")
+    .reply
+    .expect("No reply sent");
+    assert_eq!(ack.options.get_messagetype(), Some(dhcppkt::DHCPACK));
+    assert_eq!(
+        ack.options.get_raw_option(&dhcppkt::OPTION_AUTHENTICATION),
+        None
+    );
+    assert_eq!(p.get_forcerenew_leases(None), Ok(vec![]));
+
+    let mut request = mk_request(CLIENTID, "198.51.100.10".parse().unwrap());
+    request.pkt.giaddr = RELAY_IP;
+    request.pkt.options = request
+        .pkt
+        .options
+        .set_raw_option(&dhcppkt::OPTION_FORCERENEW_NONCE_CAPABLE, &[1]);
+    let ack = dhcp::handle_pkt(&mut p, &request, serverids, &conf)
+        .expect("Failed to handle request")
+        .reply
+        .expect("No reply sent");
+    assert_eq!(ack.options.get_messagetype(), Some(dhcppkt::DHCPACK));
+    let auth = ack
+        .options
+        .get_raw_option(&dhcppkt::OPTION_AUTHENTICATION)
+        .expect("No nonce sent");
+    /* Forcerenew nonce protocol, HMAC-MD5, monotonic replay detection, nonce value */
+    assert_eq!(auth[..3], [3, 1, 0]);
+    assert_eq!(auth[11], 1);
+
+    let leases = p.get_forcerenew_leases(None).expect("Failed to get leases");
+    assert_eq!(leases.len(), 1);
+    assert_eq!(leases[0].address, ack.yiaddr);
+    assert_eq!(leases[0].serverid, SERVER_IP);
+    assert_eq!(leases[0].nonce, auth[12..].to_vec());
+
+    /* The client matched the second policy */
+    assert_eq!(p.get_forcerenew_leases(Some("1")).unwrap().len(), 1);
+    assert_eq!(p.get_forcerenew_leases(Some("0")).unwrap().len(), 0);
+}
+
+#[tokio::test]
+async fn changed_policies() {
+    let old = crate::config::load_config_from_string_for_test(
+        "---
+dhcp:
+    policies:
+      - match-interface: eth0
+        apply-dns-servers: [192.0.2.53]
+        policies:
+          - { match-host-name: a, apply-address: 192.0.2.10 }
+          - { match-host-name: b, apply-address: 192.0.2.11 }
+      - match-interface: eth1
+        apply-dns-servers: [192.0.2.53]
+      - match-interface: eth2
+",
+    )
+    .expect("Failed to parse old config");
+    let new = crate::config::load_config_from_string_for_test(
+        "---
+dhcp:
+    policies:
+      - match-interface: eth0
+        apply-dns-servers: [192.0.2.53]
+        policies:
+          - { match-host-name: a, apply-address: 192.0.2.10 }
+          - { match-host-name: b, apply-address: 192.0.2.12 }
+      - match-interface: eth1
+        apply-dns-servers: [192.0.2.54]
+",
+    )
+    .expect("Failed to parse new config");
+    /* Adding a policy, and moving the others around, doesn't change them */
+    let moved = crate::config::load_config_from_string_for_test(
+        "---
+dhcp:
+    policies:
+      - match-interface: eth3
+      - match-interface: eth2
+      - match-interface: eth0
+        apply-dns-servers: [192.0.2.53]
+        policies:
+          - { match-host-name: b, apply-address: 192.0.2.11 }
+          - { match-host-name: a, apply-address: 192.0.2.10 }
+      - match-interface: eth1
+        apply-dns-servers: [192.0.2.53]
+",
+    )
+    .expect("Failed to parse moved config");
+    let old = &old.lock().await.dhcp.policies;
+    let new = &new.lock().await.dhcp.policies;
+    let moved = &moved.lock().await.dhcp.policies;
+    assert_eq!(
+        dhcp::forcerenew::changed_policies(old, old),
+        Default::default()
+    );
+    assert_eq!(
+        dhcp::forcerenew::changed_policies(old, new).changed,
+        vec!["0.1", "1", "2"]
+    );
+    assert_eq!(
+        dhcp::forcerenew::changed_policies(old, moved),
+        dhcp::forcerenew::PolicyChanges {
+            changed: vec![],
+            moved: vec![
+                ("0".into(), "2".into()),
+                ("0.0".into(), "2.1".into()),
+                ("0.1".into(), "2.0".into()),
+                ("1".into(), "3".into()),
+                ("2".into(), "1".into()),
+            ],
+        }
+    );
+}
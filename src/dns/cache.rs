@@ -47,9 +47,9 @@ pub struct CacheHandler {
 }
 
 impl CacheHandler {
-    pub fn new() -> Self {
+    pub fn new(conf: crate::config::SharedConfig) -> Self {
         CacheHandler {
-            next: outquery::OutQuery::new(),
+            next: outquery::OutQuery::new(conf),
             cache: Arc::new(RwLock::new(Cache::new())),
         }
     }
@@ -0,0 +1,366 @@
+/*   Copyright 2020 Perry Lorier
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *  SPDX-License-Identifier: Apache-2.0
+ *
+ *  DNS Configuration parsing.
+ */
+use super::dnspkt;
+use yaml_rust::yaml;
+
+pub const DNS_PORT: u16 = 53;
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidConfig(String),
+}
+
+impl Error {
+    fn annotate(&self, prefix: &str) -> Error {
+        Error::InvalidConfig(format!("{}: {}", prefix, self))
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidConfig(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+/// Queries for names in `domain` are sent to `forwarders` instead of the default forwarders.
+#[derive(Debug)]
+pub struct ConditionalForwarder {
+    pub domain: dnspkt::Domain,
+    pub forwarders: Vec<std::net::SocketAddr>,
+}
+
+/// Where to listen for queries.  If no addresses or interfaces are given, queries are accepted
+/// on every address.
+#[derive(Clone, Debug)]
+pub struct Listen {
+    pub port: u16,
+    pub addresses: Vec<std::net::IpAddr>,
+    /// Interfaces whose addresses are listened on, following them as they change.
+    pub interfaces: Vec<String>,
+}
+
+impl Default for Listen {
+    fn default() -> Self {
+        Listen {
+            port: DNS_PORT,
+            addresses: vec![],
+            interfaces: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Config {
+    /// The upstream servers to send queries to.
+    pub forwarders: Vec<std::net::SocketAddr>,
+    pub conditional_forwarders: Vec<ConditionalForwarder>,
+    pub listen: Listen,
+}
+
+impl Config {
+    /* Either an address on its own (using the DNS port), or an address and port, with IPv6
+     * addresses in brackets, eg "192.0.2.53", "192.0.2.53:5353" or "[2001:db8::53]:5353".
+     */
+    fn parse_server(fragment: &yaml::Yaml) -> Result<std::net::SocketAddr, Error> {
+        let server = fragment.as_str().ok_or_else(|| {
+            Error::InvalidConfig(format!("Expected String, got '{:?}'", fragment))
+        })?;
+        if let Ok(addr) = server.parse::<std::net::IpAddr>() {
+            return Ok(std::net::SocketAddr::new(addr, DNS_PORT));
+        }
+        server
+            .parse()
+            .map_err(|x| Error::InvalidConfig(format!("Expected address or address:port: {}", x)))
+    }
+
+    fn parse_servers(fragment: &yaml::Yaml) -> Result<Vec<std::net::SocketAddr>, Error> {
+        if let Some(servers) = fragment.as_vec() {
+            servers.iter().map(Config::parse_server).collect()
+        } else {
+            Ok(vec![Config::parse_server(fragment)?])
+        }
+    }
+
+    fn parse_conditional_forwarders(
+        fragment: &yaml::Yaml,
+    ) -> Result<Vec<ConditionalForwarder>, Error> {
+        let h = fragment.as_hash().ok_or_else(|| {
+            Error::InvalidConfig(format!("Expected hash of domains, got {:?}", fragment))
+        })?;
+        let mut conditional: Vec<ConditionalForwarder> = vec![];
+        for (k, v) in h {
+            let name = k
+                .as_str()
+                .ok_or_else(|| Error::InvalidConfig(format!("Expected domain, got {:?}", k)))?;
+            let domain: dnspkt::Domain = name
+                .parse()
+                .map_err(|e| Error::InvalidConfig(format!("Invalid domain {}: {}", name, e)))?;
+            if conditional
+                .iter()
+                .any(|c| c.domain.ends_with(&domain) && domain.ends_with(&c.domain))
+            {
+                return Err(Error::InvalidConfig(format!(
+                    "{} is listed more than once",
+                    name
+                )));
+            }
+            let forwarders = Config::parse_servers(v)
+                .map_err(|x| x.annotate(&format!("Failed to parse forwarders for {}", name)))?;
+            if forwarders.is_empty() {
+                return Err(Error::InvalidConfig(format!("No forwarders for {}", name)));
+            }
+            conditional.push(ConditionalForwarder { domain, forwarders });
+        }
+        Ok(conditional)
+    }
+
+    /// The forwarders to send a query for `domain` to, from the conditional forwarder with the
+    /// longest matching suffix, or the default forwarders if none match.
+    pub fn forwarders_for(&self, domain: &dnspkt::Domain) -> &[std::net::SocketAddr] {
+        self.conditional_forwarders
+            .iter()
+            .filter(|c| domain.ends_with(&c.domain))
+            .max_by_key(|c| c.domain.label_count())
+            .map(|c| &c.forwarders[..])
+            .unwrap_or(&self.forwarders)
+    }
+
+    fn parse_strings(fragment: &yaml::Yaml) -> Result<Vec<String>, Error> {
+        let strings = fragment
+            .as_vec()
+            .ok_or_else(|| Error::InvalidConfig(format!("Expected list, got {:?}", fragment)))?;
+        strings
+            .iter()
+            .map(|s| {
+                s.as_str()
+                    .map(String::from)
+                    .ok_or_else(|| Error::InvalidConfig(format!("Expected String, got '{:?}'", s)))
+            })
+            .collect()
+    }
+
+    fn parse_listen(fragment: &yaml::Yaml) -> Result<Listen, Error> {
+        let h = fragment
+            .as_hash()
+            .ok_or_else(|| Error::InvalidConfig("listen is expected to be a hash".into()))?;
+        let mut listen: Listen = Default::default();
+        for (k, v) in h {
+            match k.as_str() {
+                Some("port") => {
+                    listen.port = v
+                        .as_i64()
+                        .and_then(|p| std::convert::TryFrom::try_from(p).ok())
+                        .ok_or_else(|| {
+                            Error::InvalidConfig(format!("Expected port number, got {:?}", v))
+                        })?
+                }
+                Some("addresses") => {
+                    listen.addresses = Config::parse_strings(v)?
+                        .iter()
+                        .map(|a| match a.parse() {
+                            /* Without an interface there's no scope to listen on it with */
+                            Ok(std::net::IpAddr::V6(v6)) if super::is_ipv6_link_local(&v6) => {
+                                Err(Error::InvalidConfig(format!(
+                                    "Link local address {} must be listened on using interfaces",
+                                    a
+                                )))
+                            }
+                            Ok(addr) => Ok(addr),
+                            Err(x) => Err(Error::InvalidConfig(format!(
+                                "Invalid address {}: {}",
+                                a, x
+                            ))),
+                        })
+                        .collect::<Result<_, _>>()
+                        .map_err(|x| x.annotate("Failed to parse addresses"))?
+                }
+                Some("interfaces") => {
+                    listen.interfaces = Config::parse_strings(v)
+                        .map_err(|x| x.annotate("Failed to parse interfaces"))?
+                }
+                _ => {
+                    return Err(Error::InvalidConfig(format!(
+                        "Unexpected key {:?} in listen",
+                        k
+                    )))
+                }
+            }
+        }
+        Ok(listen)
+    }
+
+    fn parse_dns(fragment: &yaml::Yaml) -> Result<Config, Error> {
+        if let Some(h) = fragment.as_hash() {
+            let mut conf: Config = Default::default();
+            for (k, v) in h {
+                match k.as_str() {
+                    Some("forwarders") => {
+                        conf.forwarders = Config::parse_servers(v)
+                            .map_err(|x| x.annotate("Failed to parse forwarders"))?
+                    }
+                    Some("conditional-forwarders") => {
+                        conf.conditional_forwarders = Config::parse_conditional_forwarders(v)
+                            .map_err(|x| x.annotate("Failed to parse conditional-forwarders"))?
+                    }
+                    Some("listen") => {
+                        conf.listen = Config::parse_listen(v)
+                            .map_err(|x| x.annotate("Failed to parse listen"))?
+                    }
+                    Some(x) => {
+                        return Err(Error::InvalidConfig(format!(
+                            "Unexpected item {} in dns fragment",
+                            x
+                        )))
+                    }
+                    None => {
+                        return Err(Error::InvalidConfig(format!(
+                            "Unexpected key {:?} in dns fragment",
+                            k
+                        )))
+                    }
+                }
+            }
+            Ok(conf)
+        } else {
+            Err(Error::InvalidConfig("dns is expected to be a hash".into()))
+        }
+    }
+
+    /* The dns section is optional, as there are defaults for everything. */
+    pub fn new(y: &yaml::Yaml) -> Result<Self, Error> {
+        match y
+            .as_hash()
+            .and_then(|h| h.get(&yaml::Yaml::from_str("dns")))
+        {
+            Some(dnsconf) => Config::parse_dns(dnsconf),
+            None => Ok(Default::default()),
+        }
+    }
+}
+
+#[test]
+fn test_forwarders() {
+    let y = &yaml_rust::YamlLoader::load_from_str(
+        "
+dns:
+    forwarders: [192.0.2.53, '198.51.100.53:5353', '2001:db8::53', '[2001:db8::54]:5353']
+",
+    )
+    .unwrap()[0];
+    let conf = Config::new(y).expect("Failed to parse dns config");
+    assert_eq!(
+        conf.forwarders,
+        vec![
+            "192.0.2.53:53".parse().unwrap(),
+            "198.51.100.53:5353".parse().unwrap(),
+            "[2001:db8::53]:53".parse().unwrap(),
+            "[2001:db8::54]:5353".parse().unwrap(),
+        ]
+    );
+    assert!(Config::new(
+        &yaml_rust::YamlLoader::load_from_str("dns: {forwarders: [dns.example]}").unwrap()[0]
+    )
+    .is_err());
+}
+
+#[test]
+fn test_conditional_forwarders() {
+    let y = &yaml_rust::YamlLoader::load_from_str(
+        "
+dns:
+    forwarders: [192.0.2.53]
+    conditional-forwarders:
+        corp.example: [10.0.0.53]
+        lab.corp.example.: [10.1.0.53]
+        10.in-addr.arpa: [10.0.0.53]
+        2.0.192.in-addr.arpa: 192.0.2.54
+        8.b.d.0.1.0.0.2.ip6.arpa: ['2001:db8::53']
+",
+    )
+    .unwrap()[0];
+    let conf = Config::new(y).expect("Failed to parse dns config");
+    let lookup = |name: &str| conf.forwarders_for(&name.parse().unwrap()).to_vec();
+    let default: Vec<std::net::SocketAddr> = vec!["192.0.2.53:53".parse().unwrap()];
+    let corp: Vec<std::net::SocketAddr> = vec!["10.0.0.53:53".parse().unwrap()];
+
+    assert_eq!(lookup("www.example"), default);
+    assert_eq!(lookup("corp.example"), corp);
+    assert_eq!(lookup("WWW.Corp.Example"), corp);
+    /* Only whole labels match */
+    assert_eq!(lookup("notcorp.example"), default);
+    /* The longest suffix wins */
+    assert_eq!(
+        lookup("host.lab.corp.example"),
+        vec!["10.1.0.53:53".parse().unwrap()]
+    );
+
+    /* Reverse zones */
+    assert_eq!(lookup("4.3.2.10.in-addr.arpa"), corp);
+    assert_eq!(lookup("10.in-addr.arpa"), corp);
+    assert_eq!(lookup("4.3.2.11.in-addr.arpa"), default);
+    assert_eq!(
+        lookup("1.2.0.192.in-addr.arpa"),
+        vec!["192.0.2.54:53".parse().unwrap()]
+    );
+    assert_eq!(lookup("1.3.0.192.in-addr.arpa"), default);
+    assert_eq!(
+        lookup("3.5.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa"),
+        vec!["[2001:db8::53]:53".parse().unwrap()]
+    );
+
+    let duplicate = &yaml_rust::YamlLoader::load_from_str(
+        "dns: {conditional-forwarders: {corp.example: [10.0.0.53], CORP.example.: [10.0.0.54]}}",
+    )
+    .unwrap()[0];
+    assert!(Config::new(duplicate).is_err());
+}
+
+#[test]
+fn test_listen() {
+    let parse = |s: &str| Config::new(&yaml_rust::YamlLoader::load_from_str(s).unwrap()[0]);
+    let conf = parse("dns: {forwarders: [192.0.2.53]}").expect("Failed to parse dns config");
+    assert_eq!(conf.listen.port, 53);
+    assert!(conf.listen.addresses.is_empty());
+
+    let conf = parse(
+        "
+dns:
+    listen:
+        port: 5353
+        addresses: [192.0.2.1, '2001:db8::1']
+        interfaces: [eth0]
+",
+    )
+    .expect("Failed to parse dns config");
+    assert_eq!(conf.listen.port, 5353);
+    assert_eq!(
+        conf.listen.addresses,
+        vec![
+            "192.0.2.1".parse::<std::net::IpAddr>().unwrap(),
+            "2001:db8::1".parse().unwrap()
+        ]
+    );
+    assert_eq!(conf.listen.interfaces, vec!["eth0"]);
+
+    assert!(parse("dns: {listen: {port: 65536}}").is_err());
+    assert!(parse("dns: {listen: {addresses: [eth0]}}").is_err());
+    assert!(parse("dns: {listen: {addresses: ['fe80::1']}}").is_err());
+}
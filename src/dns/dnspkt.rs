@@ -94,6 +94,7 @@ impl fmt::Debug for Type {
 pub struct RCode(pub u16);
 pub const NOERROR: RCode = RCode(0);
 pub const FORMERR: RCode = RCode(1);
+pub const SERVFAIL: RCode = RCode(2);
 pub const NXDOMAIN: RCode = RCode(3);
 
 impl Ord for RCode {
@@ -107,6 +108,7 @@ impl ToString for RCode {
         match self {
             &FORMERR => String::from("FORMERR"),
             &NOERROR => String::from("NOERROR"),
+            &SERVFAIL => String::from("SERVFAIL"),
             &NXDOMAIN => String::from("NXDOMAIN"),
             RCode(x) => format!("#{}", x),
         }
@@ -161,6 +163,46 @@ impl Ord for Domain {
     }
 }
 
+impl std::str::FromStr for Domain {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        /* The root is written as ".", and any other name may have a trailing "." */
+        let s = s.strip_suffix('.').unwrap_or(s);
+        if s.is_empty() {
+            return Ok(Domain(vec![]));
+        }
+        s.split('.')
+            .map(|l| match l.len() {
+                0 => Err(format!("Empty label in {}", s)),
+                1..=63 => Ok(Label(l.as_bytes().to_vec())),
+                _ => Err(format!("Label {} is too long", l)),
+            })
+            .collect::<Result<_, _>>()
+            .map(Domain)
+    }
+}
+
+impl Domain {
+    /* RFC4343 Section 3: comparisons on name lookup for DNS queries should be case insensitive */
+    pub fn ends_with(&self, suffix: &Domain) -> bool {
+        self.0.len() >= suffix.0.len()
+            && self
+                .0
+                .iter()
+                .rev()
+                .zip(suffix.0.iter().rev())
+                .all(|(a, b)| a.0.eq_ignore_ascii_case(&b.0))
+    }
+
+    pub fn eq_ignore_case(&self, other: &Domain) -> bool {
+        self.0.len() == other.0.len() && self.ends_with(other)
+    }
+
+    pub fn label_count(&self) -> usize {
+        self.0.len()
+    }
+}
+
 impl fmt::Display for Domain {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
@@ -17,9 +17,14 @@
  *  Infrastructure for DNS services.
  */
 use crate::net::udp;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use tokio::io;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync;
 
 type UdpSocket = udp::UdpSocket;
 
@@ -27,9 +32,22 @@ extern crate nix;
 extern crate rand;
 
 mod cache;
+pub mod config;
 mod dnspkt;
 mod outquery;
 mod parse;
+mod tcp;
+
+/* How long a TCP connection can be idle before we close it. */
+const TCP_IDLE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+/* How many queries from one TCP connection are answered at once.  Once this many are outstanding
+ * we stop reading from the connection until one has been answered.
+ */
+const MAX_TCP_QUERIES_IN_FLIGHT: usize = 16;
+/* How often to check if the addresses we listen on should change, because an interface's
+ * addresses changed or the configuration was reloaded.
+ */
+const LISTEN_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
 
 use bytes::BytesMut;
 use tokio_util::codec::Decoder;
@@ -75,42 +93,99 @@ impl DnsServer {
 
             question: inq.question.clone(),
             answer: outr.answer.clone(),
-            nameserver: outr.answer.clone(),
+            nameserver: outr.nameserver.clone(),
             additional: outr.additional.clone(),
             edns: Some(dnspkt::EdnsData { other: vec![] }), // We should do more here.
         }
     }
 
+    fn create_servfail(&self, inq: &dnspkt::DNSPkt) -> dnspkt::DNSPkt {
+        dnspkt::DNSPkt {
+            qid: inq.qid,
+            rd: inq.rd,
+            tc: false,
+            aa: false,
+            qr: true,
+            opcode: dnspkt::OPCODE_QUERY,
+
+            cd: false,
+            ad: false,
+            ra: true,
+
+            rcode: dnspkt::SERVFAIL,
+
+            bufsize: 4096,
+
+            edns_ver: Some(0),
+            edns_do: false,
+
+            question: inq.question.clone(),
+            answer: vec![],
+            nameserver: vec![],
+            additional: vec![],
+            edns: Some(dnspkt::EdnsData { other: vec![] }),
+        }
+    }
+
+    async fn handle_inquery(&self, inquery: &dnspkt::DNSPkt) -> dnspkt::DNSPkt {
+        match self.next.handle_query(&inquery.question).await {
+            Ok(outreply) => self.create_inreply(inquery, &outreply),
+            Err(e) => {
+                /* RFC1035 Section 4.1.1: Server failure - The name server was unable to process
+                 * this query due to a problem with the name server.
+                 */
+                println!("Error: {:?}", e);
+                self.create_servfail(inquery)
+            }
+        }
+    }
+
     async fn recvinquery(
-        &mut self,
+        &self,
         responder: Arc<UdpSocket>,
         pkt: &[u8],
         from: std::net::SocketAddr,
         to: Option<std::net::IpAddr>,
     ) {
-        let inquery = parse::PktParser::new(pkt)
-            .get_dns()
-            .expect("Failed to parse InQuery"); // TODO
-        println!("InQuery {:?} {:?}", from, inquery);
-
-        match self.next.handle_query(&inquery.question).await {
-            Ok(outreply) => {
-                let inreply = self.create_inreply(&inquery, &outreply);
-                println!("InReply: {:?} <- {:?}", from, inreply);
-                let cmsg = udp::ControlMessage::new().set_send_from(to);
-                responder
-                    .send_msg(
-                        inreply.serialise().as_slice(),
-                        &cmsg,
-                        udp::MsgFlags::empty(),
-                        Some(&from),
-                    )
-                    .await
-                    .expect("Failed to send reply"); // TODO: Better error handling
-                println!("Reply sent");
+        let inquery = match parse::PktParser::new(pkt).get_dns() {
+            Ok(inquery) => inquery,
+            Err(e) => {
+                println!("Failed to parse InQuery from {:?}: {}", from, e);
+                return;
             }
-            Err(e) => println!("Error: {:?}", e),
         };
+        println!("InQuery {:?} {:?}", from, inquery);
+
+        let mut inreply = self.handle_inquery(&inquery).await;
+        /* RFC1035 Section 4.2.1: Messages carried by UDP are restricted to 512 bytes (not counting
+         * the IP or UDP headers).  Longer messages are truncated and the TC bit is set in the
+         * header.
+         *
+         * RFC6891 Section 6.2.5 lets the client advertise a larger size, and RFC7766 Section 5
+         * says the client then retries over TCP.
+         */
+        if inreply.serialise().len() > inquery.bufsize as usize {
+            inreply = dnspkt::DNSPkt {
+                tc: true,
+                answer: vec![],
+                nameserver: vec![],
+                additional: vec![],
+                ..inreply
+            };
+        }
+        println!("InReply: {:?} <- {:?}", from, inreply);
+        let cmsg = udp::ControlMessage::new().set_send_from(to);
+        if let Err(e) = responder
+            .send_msg(
+                inreply.serialise().as_slice(),
+                &cmsg,
+                udp::MsgFlags::empty(),
+                Some(&from),
+            )
+            .await
+        {
+            println!("Failed to send reply to {:?}: {}", from, e);
+        }
     }
 
     async fn run(self, sock: UdpSocket) -> Result<(), io::Error> {
@@ -118,7 +193,7 @@ impl DnsServer {
         loop {
             match shared_sock.recv_msg(4096, udp::MsgFlags::empty()).await {
                 Ok(rm) => {
-                    let mut q = self.clone();
+                    let q = self.clone();
                     let shared_responder2 = shared_sock.clone();
                     println!(
                         "Received {:?} => {:?} ({})",
@@ -142,28 +217,454 @@ impl DnsServer {
             }
         }
     }
+
+    /* RFC7766 Section 6.2.1.1: In order to achieve performance on par with UDP, DNS clients SHOULD
+     * pipeline their queries ... servers MUST process pipelined queries concurrently and send
+     * out-of-order responses.
+     *
+     * So every query gets its own task, and replies are written as they become ready.
+     */
+    async fn serve_tcp(self, stream: TcpStream, from: std::net::SocketAddr) {
+        let (mut reader, writer) = io::split(stream);
+        let writer = Arc::new(sync::Mutex::new(writer));
+        let in_flight = Arc::new(sync::Semaphore::new(MAX_TCP_QUERIES_IN_FLIGHT));
+        loop {
+            /* RFC7766 Section 6.2.3: servers MAY close idle connections */
+            let pkt = match tokio::time::timeout(TCP_IDLE_TIMEOUT, tcp::read_msg(&mut reader)).await
+            {
+                Ok(Ok(Some(pkt))) => pkt,
+                Ok(Ok(None)) | Err(_) => return,
+                Ok(Err(e)) => {
+                    println!("Error reading from {:?}: {}", from, e);
+                    return;
+                }
+            };
+            let inquery = match parse::PktParser::new(&pkt).get_dns() {
+                Ok(inquery) => inquery,
+                Err(e) => {
+                    println!("Failed to parse InQuery from {:?}: {}", from, e);
+                    return;
+                }
+            };
+            println!("InQuery (TCP) {:?} {:?}", from, inquery);
+            let permit = in_flight.clone().acquire_owned().await;
+            let q = self.clone();
+            let w = writer.clone();
+            tokio::spawn(async move {
+                let inreply = q.handle_inquery(&inquery).await;
+                println!("InReply (TCP): {:?} <- {:?}", from, inreply);
+                if let Err(e) = w
+                    .lock()
+                    .await
+                    .write_all(&tcp::frame(&inreply.serialise()))
+                    .await
+                {
+                    println!("Failed to send reply to {:?}: {}", from, e);
+                }
+                drop(permit);
+            });
+        }
+    }
+
+    async fn run_tcp(self, mut listener: TcpListener) -> Result<(), io::Error> {
+        loop {
+            match listener.accept().await {
+                Ok((stream, from)) => {
+                    tokio::spawn(self.clone().serve_tcp(stream, from));
+                }
+                Err(e) => {
+                    println!("Error {}", e);
+                }
+            }
+        }
+    }
+}
+
+/* IPv6 link local addresses are only unique on a link, so need the interface as the scope */
+fn is_ipv6_link_local(addr: &std::net::Ipv6Addr) -> bool {
+    addr.segments()[0] & 0xffc0 == 0xfe80
 }
 
-async fn run_internal() -> Result<(), Box<dyn Error>> {
-    let listener = UdpSocket::bind("[::]:1053").await?;
+/* The addresses that are configured directly.  With nothing configured, listen everywhere.
+ * IPv6 sockets also accept IPv4.
+ */
+fn static_listen_addresses(listen: &config::Listen) -> HashSet<SocketAddr> {
+    if listen.addresses.is_empty() && listen.interfaces.is_empty() {
+        return vec![SocketAddr::new(
+            std::net::Ipv6Addr::UNSPECIFIED.into(),
+            listen.port,
+        )]
+        .into_iter()
+        .collect();
+    }
+    listen
+        .addresses
+        .iter()
+        .map(|addr| SocketAddr::new(*addr, listen.port))
+        .collect()
+}
+
+/* The addresses that come from interfaces, leaving out any that are already configured
+ * directly.
+ */
+fn interface_listen_addresses(
+    listen: &config::Listen,
+    interface_addresses: &[(u32, IpAddr)],
+) -> HashSet<SocketAddr> {
+    let static_addresses = static_listen_addresses(listen);
+    interface_addresses
+        .iter()
+        .map(|(ifidx, addr)| match addr {
+            IpAddr::V6(v6) if is_ipv6_link_local(v6) => {
+                SocketAddr::V6(std::net::SocketAddrV6::new(*v6, listen.port, 0, *ifidx))
+            }
+            _ => SocketAddr::new(*addr, listen.port),
+        })
+        .filter(|addr| !static_addresses.contains(addr))
+        .collect()
+}
 
+async fn get_interface_addresses(
+    netinfo: &crate::net::netinfo::SharedNetInfo,
+    interfaces: &[String],
+) -> Vec<(u32, IpAddr)> {
+    let mut addresses = vec![];
+    for name in interfaces {
+        if let Some(ifidx) = netinfo.get_ifidx_by_name(name).await {
+            for addr in netinfo.get_addresses_by_ifidx(ifidx).await {
+                addresses.push((ifidx, addr));
+            }
+        }
+    }
+    addresses
+}
+
+/* Starts serving queries on addr over UDP and TCP, until the returned handle is aborted. */
+async fn listen_on(
+    server: DnsServer,
+    addr: SocketAddr,
+) -> Result<futures::future::AbortHandle, io::Error> {
+    let listener = UdpSocket::bind(addr).await?;
     listener.set_opt_ipv4_packet_info(true)?;
-    listener.set_opt_ipv6_packet_info(true)?;
+    if addr.is_ipv6() {
+        listener.set_opt_ipv6_packet_info(true)?;
+    }
+    let tcp_listener = TcpListener::bind(addr).await?;
 
     println!("Listening for DNS on {}", listener.local_addr()?);
+    println!(
+        "Listening for DNS over TCP on {}",
+        tcp_listener.local_addr()?
+    );
 
+    let (serve, handle) = futures::future::abortable(futures::future::try_join(
+        server.clone().run(listener),
+        server.run_tcp(tcp_listener),
+    ));
+    tokio::spawn(async move {
+        if let Ok(Err(e)) = serve.await {
+            println!("Stopped listening for DNS on {}: {}", addr, e);
+        }
+    });
+    Ok(handle)
+}
+
+/* The configured addresses are listened on once at startup, and failing to listen on one of
+ * them is fatal, as nothing will change that would let it succeed later.
+ */
+async fn listen_on_static(server: &DnsServer, listen: &config::Listen) -> Result<(), String> {
+    for addr in static_listen_addresses(listen) {
+        listen_on(server.clone(), addr)
+            .await
+            .map_err(|e| format!("Failed to listen for DNS on {}: {}", addr, e))?;
+    }
+    Ok(())
+}
+
+async fn run_internal(
+    netinfo: crate::net::netinfo::SharedNetInfo,
+    conf: crate::config::SharedConfig,
+) -> Result<(), Box<dyn Error>> {
     let server = DnsServer {
-        next: cache::CacheHandler::new(),
+        next: cache::CacheHandler::new(conf.clone()),
     };
 
-    server.run(listener).await?;
+    /* Changes to the addresses and port only take effect on restart, but the interfaces are
+     * followed as they (or the configuration) change.
+     */
+    let mut listen = conf.lock().await.dns.listen.clone();
+    listen_on_static(&server, &listen).await?;
 
-    Ok(())
+    let mut listeners: HashMap<SocketAddr, futures::future::AbortHandle> = HashMap::new();
+    let mut interval = tokio::time::interval(LISTEN_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        listen.interfaces = conf.lock().await.dns.listen.interfaces.clone();
+        let wanted = interface_listen_addresses(
+            &listen,
+            &get_interface_addresses(&netinfo, &listen.interfaces).await,
+        );
+
+        listeners.retain(|addr, handle| {
+            if !wanted.contains(addr) {
+                println!("No longer listening for DNS on {}", addr);
+                handle.abort();
+            }
+            wanted.contains(addr)
+        });
+        for addr in wanted {
+            if listeners.contains_key(&addr) {
+                continue;
+            }
+            /* If this fails (eg the address is still being configured), try again next time */
+            match listen_on(server.clone(), addr).await {
+                Ok(handle) => {
+                    listeners.insert(addr, handle);
+                }
+                Err(e) => println!("Failed to listen for DNS on {}: {}", addr, e),
+            }
+        }
+    }
 }
 
-pub async fn run() -> Result<(), String> {
-    match run_internal().await {
+pub async fn run(
+    netinfo: crate::net::netinfo::SharedNetInfo,
+    conf: crate::config::SharedConfig,
+) -> Result<(), String> {
+    match run_internal(netinfo, conf).await {
         Ok(_) => Ok(()),
         Err(e) => Err(e.to_string()),
     }
 }
+
+#[test]
+fn test_listen_addresses() {
+    let mut listen: config::Listen = Default::default();
+    let sorted = |addrs: HashSet<SocketAddr>| {
+        let mut addrs: Vec<_> = addrs.into_iter().collect();
+        addrs.sort();
+        addrs
+    };
+    assert_eq!(
+        sorted(static_listen_addresses(&listen)),
+        vec!["[::]:53".parse::<SocketAddr>().unwrap()]
+    );
+
+    listen.addresses = vec!["192.0.2.1".parse().unwrap()];
+    listen.interfaces = vec!["eth0".into()];
+    assert_eq!(
+        sorted(static_listen_addresses(&listen)),
+        vec!["192.0.2.1:53".parse::<SocketAddr>().unwrap()]
+    );
+    assert_eq!(
+        sorted(interface_listen_addresses(
+            &listen,
+            &[
+                (2, "192.0.2.1".parse().unwrap()),
+                (2, "198.51.100.1".parse().unwrap()),
+                (2, "2001:db8::1".parse().unwrap()),
+                (2, "fe80::1".parse().unwrap()),
+            ]
+        )),
+        vec![
+            "198.51.100.1:53".parse().unwrap(),
+            "[2001:db8::1]:53".parse().unwrap(),
+            SocketAddr::V6(std::net::SocketAddrV6::new(
+                "fe80::1".parse().unwrap(),
+                53,
+                0,
+                2
+            )),
+        ]
+    );
+
+    /* An interface with no addresses (yet) means not listening anywhere */
+    listen.addresses = vec![];
+    assert_eq!(sorted(static_listen_addresses(&listen)), vec![]);
+    assert_eq!(sorted(interface_listen_addresses(&listen, &[])), vec![]);
+}
+
+#[cfg(test)]
+fn mk_test_server(forwarder: SocketAddr) -> DnsServer {
+    let conf = crate::config::load_config_from_string_for_test(&format!(
+        "---
+dns:
+    forwarders: ['{}']
+",
+        forwarder
+    ))
+    .unwrap();
+    DnsServer {
+        next: cache::CacheHandler::new(conf),
+    }
+}
+
+#[cfg(test)]
+fn mk_test_query(qid: u16, qdomain: &str) -> dnspkt::DNSPkt {
+    outquery::create_outquery(
+        qid,
+        &dnspkt::Question {
+            qdomain: qdomain.parse().unwrap(),
+            qclass: dnspkt::CLASS_IN,
+            qtype: dnspkt::RR_A,
+        },
+    )
+}
+
+/* An answer to query with an A record for each of addresses */
+#[cfg(test)]
+fn mk_test_reply(query: &dnspkt::DNSPkt, addresses: usize) -> dnspkt::DNSPkt {
+    dnspkt::DNSPkt {
+        qr: true,
+        answer: (0..addresses)
+            .map(|i| dnspkt::RR {
+                domain: query.question.qdomain.clone(),
+                class: dnspkt::CLASS_IN,
+                rrtype: dnspkt::RR_A,
+                ttl: 300,
+                rdata: dnspkt::RData::Other(vec![192, 0, 2, i as u8]),
+            })
+            .collect(),
+        ..query.clone()
+    }
+}
+
+#[tokio::test]
+async fn test_tcp_pipelining() {
+    let mut upstream = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server = mk_test_server(upstream.local_addr().unwrap());
+    /* Wait for both queries, and then answer them in the opposite order to they were sent */
+    tokio::spawn(async move {
+        let mut buf = vec![0; 65536];
+        let mut queries = vec![];
+        while queries.len() < 2 {
+            let (l, client) = upstream.recv_from(&mut buf).await.unwrap();
+            queries.push((parse::PktParser::new(&buf[..l]).get_dns().unwrap(), client));
+        }
+        queries.sort_by_key(|(query, _)| query.question.qdomain.to_string() != "second.example");
+        for (query, client) in &queries {
+            upstream
+                .send_to(&mk_test_reply(query, 1).serialise(), client)
+                .await
+                .unwrap();
+        }
+    });
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(server.run_tcp(TcpListener::from_std(listener).unwrap()));
+
+    /* Both queries are sent on one connection without waiting for a reply */
+    let mut client = TcpStream::from_std(std::net::TcpStream::connect(addr).unwrap()).unwrap();
+    let mut queries = tcp::frame(&mk_test_query(1, "first.example").serialise());
+    queries.extend(tcp::frame(&mk_test_query(2, "second.example").serialise()));
+    client.write_all(&queries).await.unwrap();
+
+    let mut qids = vec![];
+    for _ in 0..2 {
+        let pkt = tokio::time::timeout(
+            std::time::Duration::from_secs(10),
+            tcp::read_msg(&mut client),
+        )
+        .await
+        .expect("Timed out waiting for reply")
+        .unwrap()
+        .expect("Connection closed");
+        let reply = parse::PktParser::new(&pkt).get_dns().unwrap();
+        assert_eq!(reply.answer.len(), 1);
+        qids.push(reply.qid);
+    }
+    /* The second query was answered first, so its reply didn't wait for the first one */
+    assert_eq!(qids, vec![2, 1]);
+}
+
+#[cfg(test)]
+async fn udp_query(
+    client: &mut tokio::net::UdpSocket,
+    server: SocketAddr,
+    query: dnspkt::DNSPkt,
+) -> dnspkt::DNSPkt {
+    client.send_to(&query.serialise(), &server).await.unwrap();
+    let mut buf = vec![0; 65536];
+    let l = tokio::time::timeout(std::time::Duration::from_secs(10), client.recv(&mut buf))
+        .await
+        .expect("Timed out waiting for reply")
+        .unwrap();
+    parse::PktParser::new(&buf[..l]).get_dns().unwrap()
+}
+
+#[tokio::test]
+async fn test_udp_truncation() {
+    let mut upstream = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server = mk_test_server(upstream.local_addr().unwrap());
+    tokio::spawn(async move {
+        let mut buf = vec![0; 65536];
+        loop {
+            let (l, client) = upstream.recv_from(&mut buf).await.unwrap();
+            let query = parse::PktParser::new(&buf[..l]).get_dns().unwrap();
+            let addresses = if query.question.qdomain.to_string() == "big.example" {
+                40
+            } else {
+                12
+            };
+            upstream
+                .send_to(&mk_test_reply(&query, addresses).serialise(), &client)
+                .await
+                .unwrap();
+        }
+    });
+
+    let listener = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(server.run(listener));
+
+    let mut client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    /* Without EDNS the client can only accept 512 bytes, so it's told to retry over TCP */
+    let reply = udp_query(
+        &mut client,
+        addr,
+        dnspkt::DNSPkt {
+            bufsize: 512,
+            edns: None,
+            ..mk_test_query(1, "big.example")
+        },
+    )
+    .await;
+    assert_eq!(reply.qid, 1);
+    assert!(reply.tc);
+    assert!(reply.answer.is_empty());
+
+    /* A client that can accept more gets the whole answer */
+    let reply = udp_query(&mut client, addr, mk_test_query(2, "big.example")).await;
+    assert_eq!(reply.qid, 2);
+    assert!(!reply.tc);
+    assert_eq!(reply.answer.len(), 40);
+
+    /* A reply that only fits in 512 bytes if each record is sent once */
+    let small = dnspkt::DNSPkt {
+        bufsize: 512,
+        edns: None,
+        ..mk_test_query(3, "small.example")
+    };
+    assert!(mk_test_reply(&small, 24).serialise().len() > 512);
+    let reply = udp_query(&mut client, addr, small).await;
+    assert_eq!(reply.qid, 3);
+    assert!(!reply.tc);
+    assert_eq!(reply.answer.len(), 12);
+    assert!(reply.nameserver.is_empty());
+}
+
+#[tokio::test]
+async fn test_listen_on_static_fails() {
+    let server = mk_test_server("192.0.2.53:53".parse().unwrap());
+    /* Something else already has the port */
+    let taken = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let listen = config::Listen {
+        port: taken.local_addr().unwrap().port(),
+        addresses: vec!["127.0.0.1".parse().unwrap()],
+        interfaces: vec![],
+    };
+    let err = listen_on_static(&server, &listen).await.unwrap_err();
+    assert!(err.starts_with("Failed to listen for DNS on 127.0.0.1:"));
+}
@@ -18,15 +18,36 @@
  */
 
 use crate::dns::rand::RngCore;
+use futures::StreamExt;
 use std::cell::Cell;
+use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::net::UdpSocket;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpStream, UdpSocket};
 use tokio::sync::Mutex;
 
 use crate::dns::dnspkt;
 use crate::dns::parse;
+use crate::dns::tcp;
 
-fn create_outquery(id: u16, q: &dnspkt::Question) -> dnspkt::DNSPkt {
+/* Used if no forwarders are configured. */
+const DEFAULT_FORWARDERS: [&str; 2] = ["8.8.8.8:53", "8.8.4.4:53"];
+/* How long to wait for a forwarder to answer before trying the next one.  This doubles each
+ * time we run out of forwarders to try, until we've tried them all MAX_ATTEMPTS times.  Earlier
+ * attempts are still listened to until the last one times out, so a slow forwarder isn't
+ * ignored.
+ */
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_ATTEMPTS: usize = 3;
+/* How long a forwarder that keeps failing to answer is avoided for, and how many failures in a
+ * row that takes.
+ */
+const FORWARDER_HOLDDOWN: Duration = Duration::from_secs(30);
+const FAILURES_BEFORE_HOLDDOWN: u32 = 3;
+
+pub fn create_outquery(id: u16, q: &dnspkt::Question) -> dnspkt::DNSPkt {
     dnspkt::DNSPkt {
         qid: id,
         rd: true,
@@ -53,40 +74,443 @@ fn create_outquery(id: u16, q: &dnspkt::Question) -> dnspkt::DNSPkt {
     }
 }
 
+/* RFC5452 Section 9.1: resolver implementations MUST match responses to all of the following
+ * attributes of the query: Source address against query destination address, Destination address
+ * against query source address, Destination port against query source port, Query ID, Query
+ * name, Query class and type.
+ *
+ * The addresses and ports are checked by the kernel as the socket is connected, and each query
+ * uses a new socket, and so a random source port.
+ */
+fn is_reply_to(reply: &dnspkt::DNSPkt, query: &dnspkt::DNSPkt) -> bool {
+    reply.qr
+        && reply.qid == query.qid
+        && reply
+            .question
+            .qdomain
+            .eq_ignore_case(&query.question.qdomain)
+        && reply.question.qclass == query.question.qclass
+        && reply.question.qtype == query.question.qtype
+}
+
+#[derive(Default)]
+struct ForwarderStats {
+    /// Smoothed round trip time, or None if it hasn't answered yet.
+    srtt: Option<Duration>,
+    /// When it last failed to answer.
+    failed: Option<Instant>,
+    /// How many times in a row it has failed to answer.
+    failures: u32,
+}
+
+impl ForwarderStats {
+    /* Smoothed the same way as TCP (RFC6298), so one slow answer doesn't move traffic away. */
+    fn record_rtt(&mut self, rtt: Duration) {
+        self.srtt = Some(match self.srtt {
+            None => rtt,
+            Some(srtt) => (srtt * 7 + rtt) / 8,
+        });
+        self.failed = None;
+        self.failures = 0;
+    }
+
+    fn record_failure(&mut self, now: Instant) {
+        self.failed = Some(now);
+        self.failures += 1;
+    }
+
+    fn is_down(&self, now: Instant) -> bool {
+        self.failures >= FAILURES_BEFORE_HOLDDOWN
+            && self
+                .failed
+                .map(|failed| now.duration_since(failed) < FORWARDER_HOLDDOWN)
+                .unwrap_or(false)
+    }
+}
+
+type Stats = HashMap<SocketAddr, ForwarderStats>;
+
+/* The order to try forwarders in: ones that are up before ones that recently failed, and then
+ * fastest first.  Forwarders we haven't heard from yet are tried first so we learn how fast they
+ * are, and ties are kept in the configured order.
+ */
+fn order_forwarders(forwarders: &[SocketAddr], stats: &Stats, now: Instant) -> Vec<SocketAddr> {
+    let mut ordered = forwarders.to_vec();
+    ordered.sort_by_key(|f| match stats.get(f) {
+        Some(s) => (s.is_down(now), s.srtt.unwrap_or_default()),
+        None => (false, Duration::default()),
+    });
+    ordered
+}
+
 #[derive(Clone)]
 pub struct OutQuery {
     rng: Arc<Mutex<Cell<rand::rngs::OsRng>>>,
+    conf: crate::config::SharedConfig,
+    stats: Arc<Mutex<Stats>>,
 }
 
 impl OutQuery {
-    pub fn new() -> Self {
+    pub fn new(conf: crate::config::SharedConfig) -> Self {
         OutQuery {
             rng: Arc::new(Mutex::new(Cell::new(rand::rngs::OsRng::default()))),
+            conf,
+            stats: Arc::new(Mutex::new(Stats::new())),
+        }
+    }
+
+    async fn get_forwarders(&self, domain: &dnspkt::Domain) -> Vec<SocketAddr> {
+        let mut forwarders = self.conf.lock().await.dns.forwarders_for(domain).to_vec();
+        if forwarders.is_empty() {
+            forwarders = DEFAULT_FORWARDERS
+                .iter()
+                .map(|f| f.parse().unwrap())
+                .collect();
         }
+        order_forwarders(&forwarders, &*self.stats.lock().await, Instant::now())
+    }
+
+    async fn query_forwarder(
+        &self,
+        server: SocketAddr,
+        oq: &dnspkt::DNSPkt,
+    ) -> Result<dnspkt::DNSPkt, std::io::Error> {
+        let mut outsock = UdpSocket::bind(if server.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        })
+        .await?;
+        outsock.connect(server).await?;
+
+        outsock.send(oq.serialise().as_slice()).await?;
+
+        let mut buf = [0; 65536];
+        loop {
+            let l = outsock.recv(&mut buf).await?;
+            match parse::PktParser::new(&buf[0..l]).get_dns() {
+                /* RFC7766 Section 5: If the response is truncated, the client ... SHOULD retry
+                 * the query over TCP.
+                 */
+                Ok(outreply) if is_reply_to(&outreply, oq) && outreply.tc => {
+                    println!("Truncated reply from {}, retrying over TCP", server);
+                    return self.query_forwarder_tcp(server, oq).await;
+                }
+                Ok(outreply) if is_reply_to(&outreply, oq) => return Ok(outreply),
+                Ok(outreply) => {
+                    println!("Ignoring mismatched reply from {}: {:?}", server, outreply)
+                }
+                Err(e) => println!("Ignoring unparsable reply from {}: {}", server, e),
+            }
+        }
+    }
+
+    async fn query_forwarder_tcp(
+        &self,
+        server: SocketAddr,
+        oq: &dnspkt::DNSPkt,
+    ) -> Result<dnspkt::DNSPkt, std::io::Error> {
+        let mut stream = TcpStream::connect(server).await?;
+        stream.write_all(&tcp::frame(&oq.serialise())).await?;
+        loop {
+            let pkt = tcp::read_msg(&mut stream).await?.ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    format!("{} closed the connection", server),
+                )
+            })?;
+            match parse::PktParser::new(&pkt).get_dns() {
+                Ok(outreply) if is_reply_to(&outreply, oq) => return Ok(outreply),
+                Ok(outreply) => {
+                    println!("Ignoring mismatched reply from {}: {:?}", server, outreply)
+                }
+                Err(e) => println!("Ignoring unparsable reply from {}: {}", server, e),
+            }
+        }
+    }
+
+    async fn record_failure(&self, server: SocketAddr) {
+        self.stats
+            .lock()
+            .await
+            .entry(server)
+            .or_default()
+            .record_failure(Instant::now());
     }
 
     pub async fn handle_query(
         &self,
         q: &dnspkt::Question,
     ) -> Result<dnspkt::DNSPkt, std::io::Error> {
-        let oq = create_outquery(self.rng.lock().await.get().next_u32() as u16, q);
+        let mut error = std::io::Error::new(std::io::ErrorKind::NotFound, "No forwarders");
+        let mut timeout = INITIAL_TIMEOUT;
+        let mut attempts = futures::stream::FuturesUnordered::new();
+        for _ in 0..MAX_ATTEMPTS {
+            for server in self.get_forwarders(&q.qdomain).await {
+                /* Every query gets a new ID, so a late reply to an earlier one isn't accepted */
+                let oq = create_outquery(self.rng.lock().await.get().next_u32() as u16, q);
+                println!("OutQuery: {} {:?}", server, oq);
 
-        let mut outsock = UdpSocket::bind("0.0.0.0:0").await?;
-        outsock.connect("8.8.8.8:53").await?;
+                let start = Instant::now();
+                attempts.push(async move {
+                    let result = self.query_forwarder(server, &oq).await;
+                    (server, start, result)
+                });
 
-        println!("OutQuery: {:?}", oq);
-        println!(
-            "OutQuery (parsed): {:?}",
-            parse::PktParser::new(&oq.serialise()).get_dns()
-        );
-        outsock.send(oq.serialise().as_slice()).await?;
+                /* Wait for a reply to this query, or to any of the earlier ones, until it's time
+                 * to try again.
+                 */
+                let deadline = start + timeout;
+                loop {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    match tokio::time::timeout(remaining, attempts.next()).await {
+                        Ok(Some((server, start, Ok(outreply)))) => {
+                            self.stats
+                                .lock()
+                                .await
+                                .entry(server)
+                                .or_default()
+                                .record_rtt(start.elapsed());
+                            return Ok(outreply);
+                        }
+                        Ok(Some((server, _, Err(e)))) => {
+                            println!("Forwarder {} failed: {}", server, e);
+                            self.record_failure(server).await;
+                            error = e;
+                        }
+                        /* Every query so far has failed, so there's no point waiting */
+                        Ok(None) => break,
+                        Err(_) => {
+                            println!("Forwarder {} timed out", server);
+                            self.record_failure(server).await;
+                            error = std::io::Error::new(
+                                std::io::ErrorKind::TimedOut,
+                                format!("Forwarder {} timed out", server),
+                            );
+                            break;
+                        }
+                    }
+                }
+            }
+            timeout *= 2;
+        }
+        Err(error)
+    }
+}
+
+#[test]
+fn test_order_forwarders() {
+    let a: SocketAddr = "192.0.2.53:53".parse().unwrap();
+    let b: SocketAddr = "198.51.100.53:53".parse().unwrap();
+    let c: SocketAddr = "[2001:db8::53]:53".parse().unwrap();
+    let now = Instant::now();
+    let mut stats = Stats::new();
+    /* Nothing known, so use the configured order */
+    assert_eq!(order_forwarders(&[a, b, c], &stats, now), vec![a, b, c]);
+
+    stats
+        .entry(a)
+        .or_default()
+        .record_rtt(Duration::from_millis(50));
+    stats
+        .entry(b)
+        .or_default()
+        .record_rtt(Duration::from_millis(10));
+    /* c hasn't been tried yet, so is tried first, then the fastest */
+    assert_eq!(order_forwarders(&[a, b, c], &stats, now), vec![c, b, a]);
 
+    stats
+        .entry(c)
+        .or_default()
+        .record_rtt(Duration::from_millis(20));
+    /* One missed reply isn't enough to avoid b, but repeatedly failing is */
+    stats.entry(b).or_default().record_failure(now);
+    assert_eq!(order_forwarders(&[a, b, c], &stats, now), vec![b, c, a]);
+    for _ in 1..FAILURES_BEFORE_HOLDDOWN {
+        stats.entry(b).or_default().record_failure(now);
+    }
+    assert_eq!(order_forwarders(&[a, b, c], &stats, now), vec![c, a, b]);
+    /* Once the holddown expires, b is fastest again */
+    assert_eq!(
+        order_forwarders(&[a, b, c], &stats, now + FORWARDER_HOLDDOWN),
+        vec![b, c, a]
+    );
+}
+
+#[tokio::test]
+async fn test_mismatched_replies() {
+    let mut upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let conf = crate::config::load_config_from_string_for_test(&format!(
+        "---
+dns:
+    forwarders: ['{}']
+",
+        upstream.local_addr().unwrap()
+    ))
+    .unwrap();
+    tokio::spawn(async move {
         let mut buf = [0; 65536];
-        let l = outsock.recv(&mut buf).await?;
-        let outreply = parse::PktParser::new(&buf[0..l])
-            .get_dns()
-            .expect("Failed to parse OutReply"); // TODO: Better error handling than panic!
+        let (l, client) = upstream.recv_from(&mut buf).await.unwrap();
+        let query = parse::PktParser::new(&buf[..l]).get_dns().unwrap();
+        let reply = |qid, qdomain: &str, rcode| {
+            dnspkt::DNSPkt {
+                qid,
+                qr: true,
+                rcode,
+                question: dnspkt::Question {
+                    qdomain: qdomain.parse().unwrap(),
+                    ..query.question.clone()
+                },
+                ..query.clone()
+            }
+            .serialise()
+        };
+        /* A reply with the wrong ID, then for the wrong question, then the real answer */
+        for pkt in &[
+            reply(query.qid.wrapping_add(1), "www.example", dnspkt::NXDOMAIN),
+            reply(query.qid, "evil.example", dnspkt::NXDOMAIN),
+            reply(query.qid, "WWW.example", dnspkt::NOERROR),
+        ] {
+            upstream.send_to(pkt, &client).await.unwrap();
+        }
+    });
+    let reply = OutQuery::new(conf)
+        .handle_query(&dnspkt::Question {
+            qdomain: "www.example".parse().unwrap(),
+            qclass: dnspkt::CLASS_IN,
+            qtype: dnspkt::RR_A,
+        })
+        .await
+        .expect("No reply");
+    assert_eq!(reply.rcode, dnspkt::NOERROR);
+}
+
+/* tokio 0.2 creates TCP sockets through net2 0.2.34 and older, which misreads the socket
+ * addresses of newer Rust standard libraries, so every connect fails with EAFNOSUPPORT.  Tests
+ * that need us to make TCP connections are skipped there, rather than failing because of the
+ * toolchain.
+ */
+#[cfg(test)]
+async fn can_connect_tcp() -> bool {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    match TcpStream::connect(listener.local_addr().unwrap()).await {
+        Err(e) if e.raw_os_error() == Some(nix::libc::EAFNOSUPPORT) => {
+            println!(
+                "Skipping test, this toolchain can't make TCP connections: {}",
+                e
+            );
+            false
+        }
+        _ => true,
+    }
+}
 
-        Ok(outreply)
+#[tokio::test]
+async fn test_truncated_reply_retries_over_tcp() {
+    if !can_connect_tcp().await {
+        return;
     }
+    /* The forwarder listens for both UDP and TCP on the same port */
+    let (udp, tcp_listener) = loop {
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        if let Ok(tcp) = std::net::TcpListener::bind(udp.local_addr().unwrap()) {
+            break (udp, tcp);
+        }
+    };
+    let conf = crate::config::load_config_from_string_for_test(&format!(
+        "---
+dns:
+    forwarders: ['{}']
+",
+        udp.local_addr().unwrap()
+    ))
+    .unwrap();
+    let mut upstream = UdpSocket::from_std(udp).unwrap();
+    let mut tcp_listener = tokio::net::TcpListener::from_std(tcp_listener).unwrap();
+    tokio::spawn(async move {
+        let mut buf = vec![0; 65536];
+        let (l, client) = upstream.recv_from(&mut buf).await.unwrap();
+        let query = parse::PktParser::new(&buf[..l]).get_dns().unwrap();
+        let truncated = dnspkt::DNSPkt {
+            qr: true,
+            tc: true,
+            ..query
+        };
+        upstream
+            .send_to(&truncated.serialise(), &client)
+            .await
+            .unwrap();
+
+        let (mut stream, _) = tcp_listener.accept().await.unwrap();
+        let pkt = tcp::read_msg(&mut stream).await.unwrap().unwrap();
+        let query = parse::PktParser::new(&pkt).get_dns().unwrap();
+        let reply = dnspkt::DNSPkt {
+            qr: true,
+            answer: vec![dnspkt::RR {
+                domain: query.question.qdomain.clone(),
+                class: dnspkt::CLASS_IN,
+                rrtype: dnspkt::RR_A,
+                ttl: 300,
+                rdata: dnspkt::RData::Other(vec![192, 0, 2, 1]),
+            }],
+            ..query
+        };
+        stream
+            .write_all(&tcp::frame(&reply.serialise()))
+            .await
+            .unwrap();
+    });
+    let reply = OutQuery::new(conf)
+        .handle_query(&dnspkt::Question {
+            qdomain: "big.example".parse().unwrap(),
+            qclass: dnspkt::CLASS_IN,
+            qtype: dnspkt::RR_A,
+        })
+        .await
+        .expect("No reply");
+    assert!(!reply.tc);
+    assert_eq!(reply.answer.len(), 1);
+}
+
+#[tokio::test]
+async fn test_slow_forwarder() {
+    /* The forwarder takes longer than the first timeout to answer, but the answer to the first
+     * query is still used.
+     */
+    let mut upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server = upstream.local_addr().unwrap();
+    let conf = crate::config::load_config_from_string_for_test(&format!(
+        "---
+dns:
+    forwarders: ['{}']
+",
+        server
+    ))
+    .unwrap();
+    tokio::spawn(async move {
+        let mut buf = vec![0; 65536];
+        let (l, client) = upstream.recv_from(&mut buf).await.unwrap();
+        let query = parse::PktParser::new(&buf[..l]).get_dns().unwrap();
+        tokio::time::delay_for(INITIAL_TIMEOUT + Duration::from_millis(200)).await;
+        let reply = dnspkt::DNSPkt {
+            qr: true,
+            rcode: dnspkt::NXDOMAIN,
+            ..query
+        };
+        upstream.send_to(&reply.serialise(), &client).await.unwrap();
+    });
+    let outquery = OutQuery::new(conf);
+    let reply = outquery
+        .handle_query(&dnspkt::Question {
+            qdomain: "slow.example".parse().unwrap(),
+            qclass: dnspkt::CLASS_IN,
+            qtype: dnspkt::RR_A,
+        })
+        .await
+        .expect("No reply");
+    assert_eq!(reply.rcode, dnspkt::NXDOMAIN);
+    /* And it answered in the end, so isn't counted as failing */
+    let stats = outquery.stats.lock().await;
+    assert!(stats[&server].srtt.unwrap() > INITIAL_TIMEOUT);
+    assert_eq!(stats[&server].failures, 0);
 }
@@ -0,0 +1,67 @@
+/*   Copyright 2020 Perry Lorier
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *  SPDX-License-Identifier: Apache-2.0
+ *
+ *  Sections quoted from RFCs are covered by the terms specified in RFC3978.
+ *
+ *  Framing for DNS messages over TCP.
+ */
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/* RFC7766 Section 8: DNS clients and servers SHOULD pass the two-octet length field, and the
+ * message described by that length field, to the TCP layer at the same time (e.g., in a single
+ * "write" system call) to make it more likely that all the data will be transmitted in a single
+ * TCP segment.
+ */
+pub fn frame(msg: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(msg.len() + 2);
+    framed.extend_from_slice(&(msg.len() as u16).to_be_bytes());
+    framed.extend_from_slice(msg);
+    framed
+}
+
+/// Reads the next message from a TCP stream, or None if the other end has closed the connection.
+pub async fn read_msg<R: AsyncRead + Unpin>(
+    stream: &mut R,
+) -> Result<Option<Vec<u8>>, std::io::Error> {
+    let mut len = [0u8; 2];
+    if stream.read(&mut len[..1]).await? == 0 {
+        return Ok(None);
+    }
+    stream.read_exact(&mut len[1..]).await?;
+    let mut msg = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut msg).await?;
+    Ok(Some(msg))
+}
+
+#[tokio::test]
+async fn test_framing() {
+    let mut stream = frame(b"first");
+    stream.extend(frame(b""));
+    stream.extend(frame(&[0x55; 300]));
+    let mut reader = &stream[..];
+    assert_eq!(
+        read_msg(&mut reader).await.unwrap(),
+        Some(b"first".to_vec())
+    );
+    assert_eq!(read_msg(&mut reader).await.unwrap(), Some(vec![]));
+    assert_eq!(read_msg(&mut reader).await.unwrap(), Some(vec![0x55; 300]));
+    assert_eq!(read_msg(&mut reader).await.unwrap(), None);
+
+    /* A connection closed part way through a message is an error */
+    let truncated = frame(b"first");
+    assert!(read_msg(&mut &truncated[..4]).await.is_err());
+}
@@ -21,3 +21,6 @@ pub mod config;
 pub mod dhcp;
 pub mod dns;
 pub mod net;
+
+#[cfg(test)]
+mod test_util;
@@ -35,10 +35,15 @@ async fn go() -> Result<(), Box<dyn Error>> {
         std::path::Path::new(&args[1])
     };
     let conf = erbium::config::load_config_from_path(config_file).await?;
+    let reloads = erbium::config::reload_on_sighup(config_file.to_path_buf(), conf.clone())?;
     let mut services = futures::stream::FuturesUnordered::new();
 
-    services.push(tokio::spawn(dhcp::run(netinfo, conf)));
-    services.push(tokio::spawn(dns::run()));
+    services.push(tokio::spawn(dhcp::run(
+        netinfo.clone(),
+        conf.clone(),
+        reloads,
+    )));
+    services.push(tokio::spawn(dns::run(netinfo, conf)));
 
     let x = services.next().await.unwrap();
     println!("Service complete: {:?}", x);
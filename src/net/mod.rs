@@ -20,7 +20,7 @@ pub mod raw;
 pub mod udp;
 
 // TODO: Write better Debug or to_string() method.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Ipv4Subnet {
     pub addr: std::net::Ipv4Addr,
     pub prefixlen: u8,
@@ -338,6 +338,18 @@ impl SharedNetInfo {
             })
             .flatten()
     }
+    pub async fn get_ifidx_by_name(&self, name: &str) -> Option<u32> {
+        self.0.read().await.name2idx.get(name).cloned()
+    }
+    pub async fn get_addresses_by_ifidx(&self, ifidx: u32) -> Vec<std::net::IpAddr> {
+        self.0
+            .read()
+            .await
+            .intf
+            .get(&ifidx)
+            .map(|x| x.addresses.iter().map(|(addr, _prefixlen)| *addr).collect())
+            .unwrap_or_default()
+    }
     pub async fn get_mtu_by_ifidx(&self, ifidx: u32) -> Option<u32> {
         self.0.read().await.intf.get(&ifidx).map(|x| x.mtu)
     }
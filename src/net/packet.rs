@@ -185,6 +185,41 @@ impl<'a> Fragment<'a> {
         let t = Tail::Fragment(Box::new(f.clone()));
         Self::new_ipv4(src.ip(), srcmac, dst.ip(), dstmac, udp_protocol, t)
     }
+
+    /// An ARP packet for IPv4 over Ethernet (without the Ethernet header).
+    pub fn new_arp<'l>(
+        op: u16,
+        sha: &[u8; 6],
+        spa: net::Ipv4Addr,
+        tha: &[u8; 6],
+        tpa: net::Ipv4Addr,
+    ) -> Fragment<'l> {
+        let mut f = Self::from_tail(Tail::None);
+        f.push_be16(0x0001); /* Hardware type: Ethernet */
+        f.push_be16(0x0800); /* Protocol type: IPv4 */
+        f.push_u8(6); /* Hardware address length */
+        f.push_u8(4); /* Protocol address length */
+        f.push_be16(op);
+        f.push_bytes(sha);
+        f.push_bytes(&spa.octets());
+        f.push_bytes(tha);
+        f.push_bytes(&tpa.octets());
+        f
+    }
+
+    /// An ICMP Echo Request (without the IPv4 header).
+    pub fn new_icmp_echo<'l>(id: u16, seq: u16, payload: Tail<'l>) -> Fragment<'l> {
+        let mut f = Self::from_tail(payload);
+        f.push_u8(8); /* Type: Echo Request */
+        f.push_u8(0); /* Code */
+        f.push_be16(0x0000); /* Checksum - filled in below */
+        f.push_be16(id);
+        f.push_be16(seq);
+        let netsum = f.netsum();
+        f.buffer[2] = (netsum >> 8) as u8;
+        f.buffer[3] = (netsum & 0xFF) as u8;
+        f
+    }
 }
 
 #[test]
@@ -205,3 +240,25 @@ fn test_checksum() {
 
     assert_eq!(finish_netsum(partial_netsum(0, &data)), 0xE5CA);
 }
+
+#[test]
+fn test_icmp_echo() {
+    let f = Fragment::new_icmp_echo(0x1234, 1, Tail::Payload(&[1, 2, 3, 4])).flatten();
+    assert_eq!(f.len(), 12);
+    /* A correct checksum makes the whole packet sum to zero */
+    assert_eq!(finish_netsum(partial_netsum(0, &f)), 0);
+}
+
+#[test]
+fn test_arp_packet() {
+    let f = Fragment::new_arp(
+        1,
+        &[2, 0, 0, 0, 0, 0],
+        net::Ipv4Addr::UNSPECIFIED,
+        &[0; 6],
+        "192.0.2.2".parse().unwrap(),
+    )
+    .flatten();
+    assert_eq!(f.len(), 28);
+    assert_eq!(&f[24..28], &[192, 0, 2, 2]);
+}
@@ -105,6 +105,51 @@ impl RawSocket {
         })
     }
 
+    /// Creates a non blocking socket for an arbitrary domain and protocol, eg an AF_PACKET socket
+    /// for ARP, or an AF_INET socket for ICMP.  `protocol` is in host byte order.
+    pub fn new_with_protocol(domain: i32, ty: i32, protocol: i32) -> Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                domain,
+                ty | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+                protocol,
+            )
+        };
+        if fd == -1 {
+            return Err(Error::last_os_error());
+        }
+        Ok(Self {
+            io: PollEvented::new(RawSocketEvented::new(fd as RawFd))?,
+        })
+    }
+
+    pub fn bind(&self, addr: &nix::sys::socket::SockAddr) -> Result<()> {
+        socket::bind(self.as_raw_fd(), addr).map_err(udp::nix_to_io_error)
+    }
+
+    pub async fn recv(&self, buf: &mut [u8], flags: MsgFlags) -> io::Result<usize> {
+        poll_fn(|cx| self.poll_recv(cx, buf, flags)).await
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+        flags: MsgFlags,
+    ) -> std::task::Poll<io::Result<usize>> {
+        ready!(self.io.poll_read_ready(cx, Ready::readable()))?;
+
+        match socket::recv(self.io.get_ref().as_raw_fd(), buf, flags) {
+            Ok(len) => std::task::Poll::Ready(Ok(len)),
+            Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => std::task::Poll::Pending,
+            Err(nix::Error::Sys(nix::errno::Errno::EAGAIN)) => {
+                self.io.clear_read_ready(cx, Ready::readable())?;
+                std::task::Poll::Pending
+            }
+            Err(e) => std::task::Poll::Ready(Err(udp::nix_to_io_error(e))),
+        }
+    }
+
     #[allow(dead_code)]
     pub fn send(&self, buf: &[u8], flags: MsgFlags) -> Result<usize> {
         socket::send(self.as_raw_fd(), buf, flags).map_err(udp::nix_to_io_error)